@@ -0,0 +1,465 @@
+use crate::block::Block;
+use crate::journal::{self, Change, Generation, State};
+use crate::plan::{self, Operation, Plan};
+use similar::TextDiff;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// Making the changes in a plan, and undoing them. Operations that are already done, like a file that already
+// has the right contents, are skipped, so applying the same config twice doesn't change anything the second time
+
+// new files get the mode they would get from a umask of 022
+const DEFAULT_MODE: u32 = 0o644;
+
+// what the operation would leave at its path, given what's there now. Commands and the environment don't change any path
+fn target(op: &Operation, current: &State) -> Result<Option<State>, String> {
+    Ok(Some(match (op, current) {
+        (Operation::WriteFile { path, .. } | Operation::Symlink { path, .. }, State::Dir) => {
+            return Err(format!("Cannot change {}, as it's a directory", path.display()));
+        },
+        (Operation::Symlink { path, .. }, State::File { .. }) => {
+            return Err(format!("Cannot link {}, as a file is already there", path.display()));
+        },
+        (Operation::Mkdir { path }, State::File { .. } | State::Link(_)) => {
+            return Err(format!("Cannot make the directory {}, as something else is already there", path.display()));
+        },
+        (Operation::WriteFile { contents, mode: Some(mode), .. }, _) => State::File { contents: contents.clone().into_bytes(), mode: *mode },
+        (Operation::WriteFile { contents, .. }, State::File { mode, .. }) => State::File { contents: contents.clone().into_bytes(), mode: *mode },
+        (Operation::WriteFile { contents, .. }, _) => State::File { contents: contents.clone().into_bytes(), mode: DEFAULT_MODE },
+        (Operation::ManagedBlock { block, contents, .. }, State::File { contents: old, mode }) => {
+            State::File { contents: block.set(text(old, op)?, Some(contents)).into_bytes(), mode: *mode }
+        },
+        (Operation::ManagedBlock { block, contents, .. }, State::Missing) => {
+            State::File { contents: block.set("", Some(contents)).into_bytes(), mode: DEFAULT_MODE }
+        },
+        (Operation::ManagedBlock { path, .. }, _) => return Err(format!("Cannot edit {}, as it isn't a file", path.display())),
+        (Operation::Symlink { target, .. }, _) => State::Link(target.clone()),
+        (Operation::Mkdir { .. }, _) => State::Dir,
+        (Operation::Remove { .. }, _) => State::Missing,
+        (Operation::Run { .. } | Operation::SetEnv { .. }, _) => return Ok(None)
+    }))
+}
+
+fn text<'a>(contents: &'a [u8], op: &Operation) -> Result<&'a str, String> {
+    std::str::from_utf8(contents).map_err(|_| format!("Cannot {op}, as it isn't text"))
+}
+
+// the changes an operation needs before it can be made: the directories it goes in, and a backup of a file or
+// link it replaces that apply didn't make
+fn prepare(op: &Operation, current: &State, generations: &[Generation], disk: &Overlay) -> Result<Vec<(PathBuf, State)>, String> {
+    let mut changes = Vec::new();
+    let (Operation::WriteFile { path, .. } | Operation::ManagedBlock { path, .. } | Operation::Symlink { path, .. } | Operation::Mkdir { path }) = op else {
+        return Ok(changes);
+    };
+    for dir in path.ancestors().skip(1) {
+        match disk.read(dir)? {
+            State::Missing => changes.push((dir.to_path_buf(), State::Dir)),
+            State::Dir => break,
+            _ => return Err(format!("Cannot make {}, as {} isn't a directory", path.display(), dir.display()))
+        }
+    }
+    changes.reverse();
+    // a link is backed up as a link to the same target
+    let replaces = matches!((op, current), (Operation::WriteFile { .. }, State::File { .. }) | (Operation::WriteFile { .. } | Operation::Symlink { .. }, State::Link(_)));
+    if replaces && !managed(path, current, generations) {
+        changes.push((backup_path(path, disk)?, current.clone()));
+    }
+    Ok(changes)
+}
+
+// whether apply made the file or link at the path, so it can be replaced without a backup
+fn managed(path: &Path, current: &State, generations: &[Generation]) -> bool {
+    if let State::File { contents, .. } = current {
+        if contents.windows(plan::HEADER.len()).any(|w| w == plan::HEADER.as_bytes()) {
+            return true;
+        }
+    }
+    generations.iter().filter(|g| g.reverted.is_none())
+        .any(|g| g.changes.iter().any(|c| c.path == path && matches!((&c.after, current), (State::File { .. }, State::File { .. }) | (State::Link(_), State::Link(_)))))
+}
+
+// the first of file.bak, file.bak.1, file.bak.2 and so on that isn't taken
+fn backup_path(path: &Path, disk: &Overlay) -> Result<PathBuf, String> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let mut backup = path.with_file_name(format!("{name}.bak"));
+    let mut i = 0;
+    while disk.read(&backup)? != State::Missing {
+        i += 1;
+        backup = path.with_file_name(format!("{name}.bak.{i}"));
+    }
+    Ok(backup)
+}
+
+// what is on disk, with the changes a dry run would have made so far laid over it
+#[derive(Default)]
+struct Overlay(HashMap<PathBuf, State>);
+
+impl Overlay {
+    fn read(&self, path: &Path) -> Result<State, String> {
+        match self.0.get(path) {
+            Some(state) => Ok(state.clone()),
+            None => State::read(path)
+        }
+    }
+}
+
+// what applying the plan would change, with a unified diff for every file that would be written or removed
+pub fn dry_run(plan: &Plan, root: &Path) -> Result<String, String> {
+    let generations = journal::list(root)?;
+    // each operation sees what the ones before it would have done
+    let mut disk = Overlay::default();
+    let mut out = String::new();
+    for op in &plan.operations {
+        match op.path() {
+            Some(path) => {
+                let current = disk.read(path)?;
+                let Some(after) = target(op, &current)? else { continue };
+                if current == after {
+                    continue;
+                }
+                for (p, state) in prepare(op, &current, &generations, &disk)? {
+                    match state {
+                        State::File { .. } | State::Link(_) => out += &format!("back up {} to {}\n", path.display(), p.display()),
+                        _ => out += &describe(&p, &State::Missing, &state)
+                    }
+                    disk.0.insert(p, state);
+                }
+                out += &describe(path, &current, &after);
+                disk.0.insert(path.to_path_buf(), after);
+            },
+            None => out += &format!("{op}\n")
+        }
+    }
+    if out.is_empty() {
+        out += "Nothing to change\n";
+    }
+    Ok(out)
+}
+
+// the difference between two states of a path, or nothing if they're the same
+pub fn describe(path: &Path, from: &State, to: &State) -> String {
+    let name = path.display().to_string();
+    let mut out = String::new();
+    match from {
+        _ if from == to => return out,
+        State::File { contents, .. } if !matches!(to, State::File { .. }) => out += &diff(contents, b"", &name, "/dev/null"),
+        State::Link(old) if !matches!(to, State::Link(_)) => out += &format!("- link {name} -> {}\n", old.display()),
+        State::Dir if *to != State::Dir => out += &format!("remove {name}\n"),
+        _ => ()
+    }
+    match to {
+        State::Missing => (),
+        State::File { contents, mode } => match from {
+            State::File { contents: old, mode: old_mode } => {
+                out += &diff(old, contents, &name, &name);
+                if mode != old_mode {
+                    out += &format!("chmod {mode:o} {name}\n");
+                }
+            },
+            _ => out += &diff(b"", contents, "/dev/null", &name)
+        },
+        State::Link(target) => {
+            if let State::Link(old) = from {
+                out += &format!("- link {name} -> {}\n", old.display());
+            }
+            out += &format!("+ link {name} -> {}\n", target.display());
+        },
+        State::Dir => out += &format!("mkdir {name}\n")
+    }
+    out
+}
+
+fn diff(old: &[u8], new: &[u8], old_name: &str, new_name: &str) -> String {
+    match (std::str::from_utf8(old), std::str::from_utf8(new)) {
+        (Ok(old), Ok(new)) => TextDiff::from_lines(old, new).unified_diff().header(old_name, new_name).missing_newline_hint(true).to_string(),
+        _ if old == new => String::new(),
+        _ => format!("binary files {old_name} and {new_name} differ\n")
+    }
+}
+
+// makes the changes in the plan, in order, calling progress before each one with whether it's already done.
+// it stops at the first that fails. The changes are recorded in a new generation in root, if there are any
+pub fn apply(plan: &Plan, root: &Path, commit: Option<String>, mut progress: impl FnMut(usize, usize, &Operation, bool)) -> Result<Option<u64>, String> {
+    let generations = journal::list(root)?;
+    let mut generation: Option<Generation> = None;
+    // the environment the commands run in
+    let mut env: Vec<(&str, Option<&str>)> = Vec::new();
+    let count = plan.operations.len();
+    for (i, op) in plan.operations.iter().enumerate() {
+        let current = match op.path() {
+            Some(path) => Some(State::read(path)?),
+            None => None
+        };
+        let after = match &current {
+            Some(current) => target(op, current)?,
+            None => None
+        };
+        let done = matches!((&current, &after), (Some(current), Some(after)) if current == after);
+        progress(i + 1, count, op, done);
+        if done {
+            continue;
+        }
+        if let Operation::SetEnv { name, value } = op {
+            env.push((name, value.as_deref()));
+            continue;
+        }
+        let g = match &mut generation {
+            Some(g) => g,
+            None => generation.insert(Generation::create(root, commit.clone())?)
+        };
+        match (op.path(), current, after) {
+            (Some(path), Some(current), Some(after)) => {
+                let mut changes: Vec<(PathBuf, State, Option<Block>)> = prepare(op, &current, &generations, &Overlay::default())?.into_iter()
+                    .map(|(p, state)| (p, state, None))
+                    .collect();
+                let block = match op {
+                    Operation::ManagedBlock { block, .. } => Some(block.clone()),
+                    _ => None
+                };
+                changes.push((path.to_path_buf(), after, block));
+                for (p, state, block) in changes {
+                    // nothing has changed yet, so the change starts out with the same state before and after
+                    let before = State::read(&p)?;
+                    g.record(Change { path: p.clone(), before: before.clone(), after: before, block })?;
+                    state.write(&p).map_err(|e| format!("Failed to {op}: {e}"))?;
+                    g.made(State::read(&p)?)?;
+                }
+            },
+            _ => if let Operation::Run { command, dir } = op {
+                g.commands.push(command.clone());
+                g.save()?;
+                run(command, dir.as_deref(), &env).map_err(|e| format!("Failed to {op}: {e}"))?;
+            }
+        }
+    }
+    match &mut generation {
+        Some(g) => {
+            g.complete = true;
+            g.save()?;
+            Ok(Some(g.number))
+        },
+        None => Ok(None)
+    }
+}
+
+// runs a command in sh, in the home directory unless another is given
+fn run(command: &str, dir: Option<&Path>, env: &[(&str, Option<&str>)]) -> Result<(), String> {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    match dir {
+        Some(dir) => { cmd.current_dir(dir); },
+        None => if let Some(home) = std::env::var_os("HOME") { cmd.current_dir(home); }
+    }
+    for (name, value) in env {
+        match value {
+            Some(value) => cmd.env(name, value),
+            None => cmd.env_remove(name)
+        };
+    }
+    let status = cmd.status().map_err(|e| e.to_string())?;
+    match status.code() {
+        Some(0) => Ok(()),
+        Some(code) => Err(format!("it exited with code {code}")),
+        None => Err("it was killed by a signal".to_string())
+    }
+}
+
+// the generations that reverting to generation `to` undoes, newest first. By default it's the newest one,
+// and 0 undoes them all
+fn to_undo(root: &Path, to: Option<u64>) -> Result<Vec<Generation>, String> {
+    let mut applied: Vec<Generation> = journal::list(root)?.into_iter().filter(|g| g.reverted.is_none()).collect();
+    let to = match to {
+        Some(0) => 0,
+        Some(n) if applied.iter().any(|g| g.number == n) => n,
+        Some(n) => return Err(format!("There is no applied generation {n}")),
+        None => match applied.len() {
+            0 => return Ok(Vec::new()),
+            len => applied[..len - 1].last().map_or(0, |g| g.number)
+        }
+    };
+    applied.retain(|g| g.number > to);
+    applied.reverse();
+    Ok(applied)
+}
+
+// the paths that were changed by hand since they were applied, which reverting would lose
+fn conflicts(generations: &[Generation]) -> Result<Vec<String>, String> {
+    // what each path will be once the newer generations are undone
+    let mut expected: HashMap<PathBuf, State> = HashMap::new();
+    let mut conflicts = Vec::new();
+    for g in generations {
+        for change in g.changes.iter().rev() {
+            let current = match expected.remove(&change.path) {
+                Some(state) => state,
+                None => State::read(&change.path)?
+            };
+            if changed_by_hand(change, &current) {
+                conflicts.push(format!("{} was changed after generation {} was applied", change.path.display(), g.number));
+            }
+            expected.insert(change.path.clone(), undone(change, &current)?);
+        }
+    }
+    Ok(conflicts)
+}
+
+// what reverting would change, as with dry_run
+pub fn revert_dry_run(root: &Path, to: Option<u64>, force: bool) -> Result<String, String> {
+    let generations = to_undo(root, to)?;
+    check_conflicts(&generations, force)?;
+    let mut out = String::new();
+    let mut expected: HashMap<PathBuf, State> = HashMap::new();
+    for g in &generations {
+        for change in g.changes.iter().rev() {
+            let current = match expected.remove(&change.path) {
+                Some(state) => state,
+                None => State::read(&change.path)?
+            };
+            let state = undone(change, &current)?;
+            out += &describe(&change.path, &current, &state);
+            expected.insert(change.path.clone(), state);
+        }
+        out += &commands_note(g);
+    }
+    if out.is_empty() {
+        out += "Nothing to revert\n";
+    }
+    Ok(out)
+}
+
+// whether a change was changed since it was made. A change that apply didn't get to make is already undone.
+// only the block matters for a change to a managed block, as the rest of the file isn't apply's
+fn changed_by_hand(change: &Change, current: &State) -> bool {
+    match &change.block {
+        Some(block) => {
+            let current = block_contents(block, current);
+            current != block_contents(block, &change.after) && current != block_contents(block, &change.before)
+        },
+        None => *current != change.after && *current != change.before
+    }
+}
+
+// what reverting a change puts at its path. Reverting a change to a managed block only puts back the block,
+// keeping anything else that was changed in the file since
+fn undone(change: &Change, current: &State) -> Result<State, String> {
+    let Some(block) = &change.block else { return Ok(change.before.clone()) };
+    let State::File { contents, mode } = current else { return Ok(current.clone()) };
+    let text = std::str::from_utf8(contents).map_err(|_| format!("Cannot revert {}, as it isn't text", change.path.display()))?;
+    let text = block.set(text, block_contents(block, &change.before).as_deref());
+    // a file that was made for the block goes away with it
+    if text.is_empty() && change.before == State::Missing {
+        return Ok(State::Missing);
+    }
+    Ok(State::File { contents: text.into_bytes(), mode: *mode })
+}
+
+fn block_contents(block: &Block, state: &State) -> Option<String> {
+    match state {
+        State::File { contents, .. } => std::str::from_utf8(contents).ok().and_then(|t| block.contents(t)),
+        _ => None
+    }
+}
+
+fn check_conflicts(generations: &[Generation], force: bool) -> Result<(), String> {
+    let conflicts = conflicts(generations)?;
+    if conflicts.is_empty() || force {
+        return Ok(());
+    }
+    Err(format!("{}\nReverting would lose these changes. Use --force to revert anyway", conflicts.join("\n")))
+}
+
+pub fn commands_note(g: &Generation) -> String {
+    g.commands.iter().map(|c| format!("generation {} ran `{c}`, which can't be undone\n", g.number)).collect()
+}
+
+// puts back what was there before the generations after `to`, newest first, and marks them as reverted.
+// returns the generations that were undone
+pub fn revert(root: &Path, to: Option<u64>, force: bool) -> Result<Vec<Generation>, String> {
+    let generations = to_undo(root, to)?;
+    check_conflicts(&generations, force)?;
+    let newest = journal::list(root)?.last().map_or(0, |g| g.number);
+    let mut reverted = Vec::new();
+    for mut g in generations {
+        for change in g.changes.iter().rev() {
+            undone(change, &State::read(&change.path)?)?.write(&change.path)?;
+        }
+        g.reverted = Some(newest);
+        g.save()?;
+        reverted.push(g);
+    }
+    Ok(reverted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch;
+
+    fn block(name: &str) -> Block {
+        Block { name: name.to_string(), comment: "#".to_string() }
+    }
+
+    #[test]
+    fn dry_run_makes_parent_directories_once() {
+        let dir = scratch::dir("apply", "parents");
+        let write = |name: &str| Operation::WriteFile { path: dir.join("a/b").join(name), contents: "x\n".to_string(), mode: None };
+        let plan = Plan { operations: vec![write("one"), write("two")] };
+        let out = dry_run(&plan, &dir.join("generations")).unwrap();
+        assert_eq!(out.matches(&format!("mkdir {}\n", dir.join("a").display())).count(), 1);
+        assert_eq!(out.matches(&format!("mkdir {}\n", dir.join("a/b").display())).count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn dry_run_sees_earlier_blocks_in_the_same_file() {
+        let dir = scratch::dir("apply", "blocks");
+        let path = dir.join("rc");
+        let managed = |name: &str| Operation::ManagedBlock { path: path.clone(), block: block(name), contents: format!("{name}\n") };
+        let plan = Plan { operations: vec![managed("one"), managed("two")] };
+        let out = dry_run(&plan, &dir.join("generations")).unwrap();
+        // the second block's diff has the first as context, rather than adding it again
+        assert_eq!(out.matches("+# >>> configurator: one >>>").count(), 1);
+        assert_eq!(out.matches(" # >>> configurator: one >>>").count(), 1);
+        assert_eq!(out.matches("+# >>> configurator: two >>>").count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn replaced_links_are_backed_up_and_restored() {
+        let dir = scratch::dir("apply", "links");
+        let root = dir.join("generations");
+        let path = dir.join("config");
+        std::os::unix::fs::symlink("/elsewhere", &path).unwrap();
+        let plan = Plan { operations: vec![Operation::WriteFile { path: path.clone(), contents: "x\n".to_string(), mode: None }] };
+        apply(&plan, &root, None, |_, _, _, _| ()).unwrap();
+        assert_eq!(State::read(&dir.join("config.bak")).unwrap(), State::Link(PathBuf::from("/elsewhere")));
+        assert!(matches!(State::read(&path).unwrap(), State::File { .. }));
+        revert(&root, Some(0), false).unwrap();
+        assert_eq!(State::read(&path).unwrap(), State::Link(PathBuf::from("/elsewhere")));
+        assert_eq!(State::read(&dir.join("config.bak")).unwrap(), State::Missing);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn gc_keeps_the_applied_generation_for_revert() {
+        let dir = scratch::dir("apply", "gc");
+        let root = dir.join("generations");
+        let path = dir.join("config");
+        let write = |contents: &str| Plan { operations: vec![Operation::WriteFile { path: path.clone(), contents: contents.to_string(), mode: None }] };
+        let read = || std::fs::read_to_string(&path).unwrap();
+        apply(&write("1\n"), &root, None, |_, _, _, _| ()).unwrap();
+        apply(&write("2\n"), &root, None, |_, _, _, _| ()).unwrap();
+        revert(&root, None, false).unwrap();
+        assert_eq!(read(), "1\n");
+        // generation 2 is the newest, and 1 is the one that's applied
+        assert_eq!(journal::gc(&root, 1).unwrap(), Vec::<u64>::new());
+        apply(&write("3\n"), &root, None, |_, _, _, _| ()).unwrap();
+        assert_eq!(journal::gc(&root, 0).unwrap(), [1, 2]);
+        revert(&root, None, false).unwrap();
+        assert_eq!(read(), "1\n");
+        // what generation 1 did can't be undone any more
+        assert!(revert(&root, None, false).unwrap().is_empty());
+        assert_eq!(read(), "1\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
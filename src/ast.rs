@@ -1,10 +1,92 @@
-use std::collections::HashMap;
+use indexmap::IndexMap;
+use crate::builtins::Builtin;
 use std::cmp::Ordering;
 use std::cmp::PartialEq;
+use std::hash::{Hash, Hasher};
 use std::mem::discriminant;
+use std::fmt;
 
 pub type Ident = String;
 
+// records keep their fields in the order they were written, so output is stable between runs
+pub type RecordMap<V> = IndexMap<Ident, V>;
+
+// how record fields are ordered when a value is printed or serialized
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum KeyOrder {
+    // the order the fields were written in the source
+    #[default]
+    Insertion,
+    // sorted by key
+    Sorted
+}
+
+// byte offsets into the source file
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize
+}
+
+impl Span {
+    // 1-indexed line and column of the start of the span
+    pub fn line_col(&self, source: &str) -> (usize, usize) {
+        let before = &source[..self.start.min(source.len())];
+        let line = before.matches('\n').count() + 1;
+        let col = before.chars().rev().take_while(|c| *c != '\n').count() + 1;
+        (line, col)
+    }
+}
+
+// a problem with the source, with a hint about how to fix it. Errors are made without a span
+// where it isn't known, and the span of the expression they come from is added on the way out
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub span: Option<Span>,
+    pub message: String,
+    pub hint: Option<String>
+}
+
+impl Diagnostic {
+    pub fn new(span: Span, message: &str, hint: Option<&str>) -> Diagnostic {
+        Diagnostic { span: Some(span), message: message.to_string(), hint: hint.map(str::to_string) }
+    }
+
+    pub fn or_at(self, span: Span) -> Diagnostic {
+        Diagnostic { span: self.span.or(Some(span)), ..self }
+    }
+}
+
+impl From<String> for Diagnostic {
+    fn from(message: String) -> Diagnostic {
+        Diagnostic { span: None, message, hint: None }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+// all the errors in a file, one per line, followed by their hints
+pub fn report(file: &str, source: &str, diagnostics: &[Diagnostic]) -> String {
+    let lines: Vec<String> = diagnostics.iter().map(|d| {
+        let location = match d.span {
+            Some(span) => {
+                let (line, col) = span.line_col(source);
+                format!("{file}:{line}:{col}")
+            },
+            None => file.to_string()
+        };
+        match &d.hint {
+            Some(hint) => format!("{location}: {}\n    {hint}", d.message),
+            None => format!("{location}: {}", d.message)
+        }
+    }).collect();
+    lines.join("\n")
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Bop {
     Eq,
@@ -21,7 +103,29 @@ pub enum Bop {
     And,
     Or,
     Xor,
-    Pow
+    Pow,
+    Coalesce,
+    SafeAccess,
+    In,
+    NotIn
+}
+
+impl Bop {
+    // how tightly the operator binds, higher is tighter. Accesses are written straight after a term, so they bind tightest
+    pub fn precedence(self) -> u8 {
+        match self {
+            Bop::Coalesce => 1,
+            Bop::Or => 2,
+            Bop::Xor => 3,
+            Bop::And => 4,
+            Bop::Eq | Bop::Neq => 5,
+            Bop::Lt | Bop::Gt | Bop::Lte | Bop::Gte | Bop::In | Bop::NotIn => 6,
+            Bop::Plus | Bop::Minus => 7,
+            Bop::Times | Bop::Div => 8,
+            Bop::Pow => 9,
+            Bop::Access | Bop::SafeAccess => 10
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -30,6 +134,7 @@ pub enum Uop {
     Not
 }
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Eq, Clone, Debug)]
 pub enum Type {
     Null,
@@ -40,14 +145,53 @@ pub enum Type {
     Number,
     Function(Box<Type>, Box<Type>),
     Bool,
-    Record(HashMap<Ident, Type>),
+    Record(RecordMap<Type>),
     Text,
+    Color,
+    Version,
     Alternative(Box<Type>, Box<Type>),
     Any,
     Type,
-    Ident(Ident)
+    Ident(Ident),
+    Refined(Box<Type>, Refinement)
 }
 
+// an sRGB color with an alpha channel
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8
+}
+
+// written as hex, leaving out the alpha channel when the color is opaque
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{:02x}{:02x}{:02x}", self.r, self.g, self.b)?;
+        if self.a != 255 {
+            write!(f, "{:02x}", self.a)?;
+        }
+        Ok(())
+    }
+}
+
+// a predicate that values of a type must satisfy, checked by the interpreter
+#[derive(Debug, Clone)]
+pub struct Refinement {
+    pub binder: Ident,
+    pub pred: Box<Expr>
+}
+
+// refinements don't take part in typechecking, so only the underlying types are compared
+impl PartialEq for Refinement {
+    fn eq(&self, other: &Self) -> bool {
+        self.binder == other.binder
+    }
+}
+
+impl Eq for Refinement {}
+
 pub trait JoinSemiLattice : PartialOrd {
     fn lub(&self, other: &Self) -> Self;
 }
@@ -106,49 +250,44 @@ impl PartialOrd for Type {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         use Ordering::*;
         match (self, other) {
+            (Type::Refined(t1, _), t2) => (**t1).partial_cmp(t2),
+            (t1, Type::Refined(t2, _)) => t1.partial_cmp(&**t2),
             //any
             (Type::Any, Type::Any) => Some(Equal),
             (_, Type::Any) => Some(Less),
             (Type::Any, _) => Some(Greater),
+            //equal type
+            (t1, t2) if t1 == t2 => Some(Equal),
             //numbers
             (Type::Number, Type::Natural) | (Type::Number, Type::Real) | (Type::Number, Type::Integer) => Some(Greater),
             (Type::Natural, Type::Number) | (Type::Real, Type::Number) | (Type::Integer, Type::Number) => Some(Less),
             (Type::Integer, Type::Natural) | (Type::Real, Type::Integer) | (Type::Real, Type::Natural) => Some(Greater),
             (Type::Natural, Type::Integer) | (Type::Integer, Type::Real) | (Type::Natural, Type::Real) => Some(Less),
             //list
-            (Type::List(t1), Type::List(t2)) => (*t1).partial_cmp(&*t2),
+            (Type::List(t1), Type::List(t2)) => t1.partial_cmp(t2),
             //record
             (Type::Record(map1), Type::Record(map2)) if map1 == map2 => Some(Equal),
             (Type::Record(map1), Type::Record(map2)) => {
-                let smaller;
-                let larger;
-                let result;
-                if map1.len() < map2.len() {
-                    smaller = map1;
-                    larger = map2;
-                    result = Some(Greater);
-                } else {
-                    smaller = map2;
-                    larger = map1;
-                    result = Some(Less);
-                }
-                let large_has_small = smaller.iter().all(|(key, val)| larger.get(key).map_or(false, |v| val == v));
-                if large_has_small {
-                    result
-                } else{
-                    None
+                // a record with more fields, each of a more specific type, is a more specific record
+                let smaller = map2.iter().all(|(key, val)| map1.get(key).is_some_and(|v| v <= val));
+                let larger = map1.iter().all(|(key, val)| map2.get(key).is_some_and(|v| v <= val));
+                match (smaller, larger) {
+                    (true, true) => Some(Equal),
+                    (true, false) => Some(Less),
+                    (false, true) => Some(Greater),
+                    (false, false) => None
                 }
             }
             //alternative: todo
-            (t, Type::Alternative(t1, t2)) if t <= &*t1  || t <= &*t2 => Some(Less),
-            (t, Type::Alternative(t1, t2)) if t >= &*t1 && t >= &*t2 => Some(Greater),
-            (Type::Alternative(t1, t2), t) if t <= &*t1  || t <= &*t2 => Some(Greater),
-            (Type::Alternative(t1, t2), t) if t >= &*t1  && t >= &*t2 => Some(Less),
+            (t, Type::Alternative(t1, t2)) if t <= t1  || t <= t2 => Some(Less),
+            (t, Type::Alternative(t1, t2)) if t >= t1 && t >= t2 => Some(Greater),
+            (Type::Alternative(t1, t2), t) if t <= t1  || t <= t2 => Some(Greater),
+            (Type::Alternative(t1, t2), t) if t >= t1  && t >= t2 => Some(Less),
             (Type::Alternative(t1,t2), Type::Alternative(t3,t4)) => {
-                let c13 = (*t1).partial_cmp(&*t3);
-                let c14 = (*t1).partial_cmp(&*t4);
-                let c23 = (*t2).partial_cmp(&*t3);
-                let c24 = (*t2).partial_cmp(&*t4);
+                let c13 = t1.partial_cmp(t3);
+                let c14 = t1.partial_cmp(t4);
+                let c23 = t2.partial_cmp(t3);
+                let c24 = t2.partial_cmp(t4);
                 // TODO: I think this is wrong
                 match (c13, c14, c23, c24) {
                     (Some(x), _, _, Some(y)) if x == y => Some(x),
@@ -159,8 +298,6 @@ impl PartialOrd for Type {
                 // Number | Bool 
             },
             //unknown: todo
-            //equal type
-            (t1, t2) if t1 == t2 => Some(Ordering::Equal),
             _ => None
         }
     }
@@ -169,6 +306,7 @@ impl PartialOrd for Type {
 impl PartialEq for Type {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
+            (Type::Refined(a, _), t) | (t, Type::Refined(a, _)) => **a == *t,
             (Type::Alternative(a,b), Type::Alternative(c,d)) => {
                 *a == *c && *b == *d || *a == *d && *b == *c
             },
@@ -203,6 +341,7 @@ impl PartialEq for Type {
 #[derive(Debug, Clone)]
 pub struct Expr {
     pub t: Option<Type>,
+    pub span: Span,
     pub expr: ExprKind<Self>
 }
 
@@ -217,11 +356,13 @@ pub struct TypedExpr {
 pub enum ExprKind<Wrapper> {
     Let(Ident, Option<Type>, Box<Wrapper>, Box<Wrapper>),
     If(Box<Wrapper>, Box<Wrapper>, Box<Wrapper>),
+    // condition, message, body
+    Assert(Box<Wrapper>, Box<Wrapper>, Box<Wrapper>),
     App(Box<Wrapper>, Box<Wrapper>),
     Binop(Box<Wrapper>, Bop, Box<Wrapper>),
     Unop(Uop, Box<Wrapper>),
     Ident(Ident),
-    Record(HashMap<Ident, Wrapper>),
+    Record(RecordMap<Wrapper>),
     List(Vec<Wrapper>),
     Text(String),
     Int(i64),
@@ -229,21 +370,205 @@ pub enum ExprKind<Wrapper> {
     Boolean(bool),
     Lambda(Ident,Option<Type>,Box<Wrapper>),
     Null,
-    //Color, Version, Path
+    Color(Color),
+    Version(semver::Version),
+    // a type alias, in scope in the rest of the file
+    TypeDecl(Ident, Type, Box<Wrapper>),
+    //Path
 }
 
 
 #[derive(Debug, Clone)]
 pub enum Value {
-    Record(HashMap<Ident, Value>),
+    Record(RecordMap<Value>),
     List(Vec<Value>),
     Text(String),
     Int(i64),
     Float(f64),
     Boolean(bool),
-    Lambda(Ident,Expr),
+    Lambda(Ident,Option<Type>,Box<Expr>),
+    Builtin(Builtin),
     Null,
-    //Color, Version, Path
+    Color(Color),
+    Version(semver::Version),
+    //Path
+}
+
+impl Value {
+    // values of different kinds are ordered by kind. Integers and floats are the same kind
+    fn rank(&self) -> u8 {
+        match self {
+            Value::Null => 0,
+            Value::Boolean(_) => 1,
+            Value::Int(_) | Value::Float(_) => 2,
+            Value::Text(_) => 3,
+            Value::Version(_) => 4,
+            Value::Color(_) => 5,
+            Value::List(_) => 6,
+            Value::Record(_) => 7,
+            Value::Lambda(..) | Value::Builtin(_) => 8
+        }
+    }
+
+    // reorder the fields of every record in the value
+    pub fn with_key_order(self, order: KeyOrder) -> Value {
+        match self {
+            Value::Record(hm) => {
+                let mut hm: RecordMap<Value> = hm.into_iter().map(|(k, v)| (k, v.with_key_order(order))).collect();
+                if order == KeyOrder::Sorted {
+                    hm.sort_keys();
+                }
+                Value::Record(hm)
+            },
+            Value::List(l) => Value::List(l.into_iter().map(|v| v.with_key_order(order)).collect()),
+            v => v
+        }
+    }
+
+    // whether == makes sense between the two values. Anything can be compared to null
+    pub fn comparable(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Lambda(..) | Value::Builtin(_), _) | (_, Value::Lambda(..) | Value::Builtin(_)) => false,
+            (Value::Null, _) | (_, Value::Null) => true,
+            (a, b) => a.rank() == b.rank()
+        }
+    }
+}
+
+// Structural equality, which is the total order below. Numbers are equal if they have the same value, whether they
+// are integers or floats. Lambdas are all equal, as there is nothing meaningful to compare, and the language refuses to
+// compare them, so this only shows when values are sorted or used as keys
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Value {}
+
+// -0.0 is the same as 0.0, and every NaN is the same and greater than every other number
+fn float_key(n: f64) -> f64 {
+    if n.is_nan() {
+        f64::NAN
+    } else if n == 0.0 {
+        0.0
+    } else {
+        n
+    }
+}
+
+fn float_cmp(a: f64, b: f64) -> Ordering {
+    float_key(a).total_cmp(&float_key(b))
+}
+
+// compares exactly, where converting the integer to a float could round it
+fn int_float_cmp(a: i64, b: f64) -> Ordering {
+    // 2^63, the smallest float above every i64
+    const LIMIT: f64 = 9223372036854775808.0;
+    if b.is_nan() || b >= LIMIT {
+        Ordering::Less
+    } else if b < -LIMIT {
+        Ordering::Greater
+    } else {
+        // b is in range, so its integer part converts exactly
+        let whole = b.trunc();
+        a.cmp(&(whole as i64)).then_with(|| 0.0_f64.total_cmp(&float_key(b - whole)))
+    }
+}
+
+// A total order, so that values can be sorted and used as keys.
+// Records are ordered by their sorted fields, so field order doesn't matter
+impl Ord for Value {
+    fn cmp(&self, other: &Self) -> Ordering {
+        use Value::*;
+        match (self, other) {
+            (Boolean(a), Boolean(b)) => a.cmp(b),
+            (Int(a), Int(b)) => a.cmp(b),
+            (Float(a), Float(b)) => float_cmp(*a, *b),
+            (Int(a), Float(b)) => int_float_cmp(*a, *b),
+            (Float(a), Int(b)) => int_float_cmp(*b, *a).reverse(),
+            (Text(a), Text(b)) => a.cmp(b),
+            (Color(a), Color(b)) => a.cmp(b),
+            (Version(a), Version(b)) => a.cmp(b),
+            (List(a), List(b)) => a.cmp(b),
+            (Record(a), Record(b)) => sorted(a).cmp(&sorted(b)),
+            (a, b) => a.rank().cmp(&b.rank())
+        }
+    }
+}
+
+fn sorted(hm: &RecordMap<Value>) -> Vec<(&Ident, &Value)> {
+    let mut fields: Vec<(&Ident, &Value)> = hm.iter().collect();
+    fields.sort();
+    fields
+}
+
+// consistent with equality, so a float that equals an integer hashes like it
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        use Value::*;
+        self.rank().hash(state);
+        match self {
+            Boolean(b) => b.hash(state),
+            Int(n) => n.hash(state),
+            Float(n) if n.fract() == 0.0 && int_float_cmp(*n as i64, *n) == Ordering::Equal => (*n as i64).hash(state),
+            Float(n) => float_key(*n).to_bits().hash(state),
+            Text(t) => t.hash(state),
+            Color(c) => c.hash(state),
+            Version(v) => v.hash(state),
+            List(l) => l.hash(state),
+            Record(hm) => sorted(hm).hash(state),
+            Null | Lambda(..) | Builtin(_) => ()
+        }
+    }
+}
+
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
 }
 
 // do we consider a function to be a value
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::hash_map::DefaultHasher;
+
+    fn hash(v: &Value) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        v.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn floats_are_totally_ordered() {
+        assert_eq!(Value::Float(f64::NAN), Value::Float(f64::NAN));
+        assert_eq!(Value::Float(-0.0), Value::Float(0.0));
+        assert!(Value::Float(f64::NAN) > Value::Float(f64::INFINITY));
+        assert!(Value::Float(f64::NAN) > Value::Int(i64::MAX));
+    }
+
+    #[test]
+    fn integers_and_floats_compare_exactly() {
+        let big = 1 << 53;
+        assert_eq!(Value::Int(big), Value::Float(big as f64));
+        // big + 1 rounds to big as a float
+        assert!(Value::Int(big + 1) > Value::Float(big as f64));
+        assert!(Value::Int(i64::MAX) < Value::Float(9223372036854775808.0));
+        assert!(Value::Int(-1) > Value::Float(-1.5));
+        assert!(Value::Int(1) < Value::Float(1.5));
+        assert!(Value::Int(i64::MIN) > Value::Float(f64::NEG_INFINITY));
+    }
+
+    #[test]
+    fn equal_values_hash_the_same() {
+        assert_eq!(hash(&Value::Int(3)), hash(&Value::Float(3.0)));
+        assert_eq!(hash(&Value::Float(-0.0)), hash(&Value::Float(0.0)));
+        let a: RecordMap<Value> = [("x".to_string(), Value::Int(1)), ("y".to_string(), Value::Null)].into_iter().collect();
+        let b: RecordMap<Value> = a.iter().rev().map(|(k, v)| (k.clone(), v.clone())).collect();
+        assert_eq!(Value::Record(a.clone()), Value::Record(b.clone()));
+        assert_eq!(hash(&Value::Record(a)), hash(&Value::Record(b)));
+    }
+}
@@ -0,0 +1,74 @@
+// Managed blocks: the part of a file that apply owns, between two marker comments, in a file that
+// something else owns, like a shell rc file. The rest of the file is never changed:
+//   # >>> configurator: aliases >>>
+//   alias ls=eza
+//   # <<< configurator: aliases <<<
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    // a file can have several blocks, with different names
+    pub name: String,
+    // how a line comment starts in the file
+    pub comment: String
+}
+
+impl Block {
+    fn begin(&self) -> String {
+        format!("{} >>> configurator: {} >>>", self.comment, self.name)
+    }
+
+    fn end(&self) -> String {
+        format!("{} <<< configurator: {} <<<", self.comment, self.name)
+    }
+
+    // where the block is in the text, from the start of its first marker to the end of the line of its last.
+    // the markers have to be on lines of their own
+    fn find(&self, text: &str) -> Option<(usize, usize)> {
+        let (begin, end) = (self.begin(), self.end());
+        let mut start = None;
+        let mut pos = 0;
+        for line in text.split_inclusive('\n') {
+            let trimmed = line.trim();
+            if trimmed == begin && start.is_none() {
+                start = Some(pos);
+            } else if trimmed == end {
+                if let Some(start) = start {
+                    return Some((start, pos + line.len()));
+                }
+            }
+            pos += line.len();
+        }
+        None
+    }
+
+    // what is between the markers, if the block is in the text
+    pub fn contents(&self, text: &str) -> Option<String> {
+        let (start, end) = self.find(text)?;
+        let block = &text[start..end];
+        let inner = block.split_once('\n').map_or("", |(_, rest)| rest);
+        Some(inner[..inner.rfind(&self.end()).unwrap_or(0)].to_string())
+    }
+
+    // the text with the block's contents replaced, or the block taken out if there are no contents.
+    // a new block goes at the end, after a blank line, which is taken out again with it
+    pub fn set(&self, text: &str, contents: Option<&str>) -> String {
+        let block = contents.map(|c| {
+            let newline = if c.is_empty() || c.ends_with('\n') { "" } else { "\n" };
+            format!("{}\n{c}{newline}{}\n", self.begin(), self.end())
+        });
+        match (self.find(text), block) {
+            (Some((start, end)), Some(block)) => format!("{}{block}{}", &text[..start], &text[end..]),
+            (Some((start, end)), None) => {
+                let before = &text[..start];
+                let before = before.strip_suffix('\n').filter(|b| b.ends_with('\n')).unwrap_or(before);
+                format!("{before}{}", &text[end..])
+            },
+            (None, Some(block)) => match text {
+                "" => block,
+                _ if text.ends_with('\n') => format!("{text}\n{block}"),
+                _ => format!("{text}\n\n{block}")
+            },
+            (None, None) => text.to_string()
+        }
+    }
+}
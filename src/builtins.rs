@@ -1,6 +1,160 @@
 use std::collections::HashMap;
-use crate::ast::{Ident, Type};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use crate::ast::{Ident, Type, Value};
+use crate::output::shell::{quote, Shell};
+use crate::input;
+use crate::printer::value_source;
 
 // Built in functions
-//TODO: need to separate types of builtins (for typechecking) and definitions
-// pub let BUILTINS: HashMap<Ident, Type> = HashMap::new();
\ No newline at end of file
+// the types are used by the typechecker, and the definitions by the interpreter
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Builtin {
+    // quote text so it can be used as a single word in a POSIX shell command
+    ShellEscape,
+    // parse text in another format into a value
+    FromJson,
+    FromToml,
+    FromYaml,
+    // read a file in another format into a value
+    ImportJson(Rc<Imports>),
+    ImportToml(Rc<Imports>),
+    ImportYaml(Rc<Imports>)
+}
+
+// where the import builtins look for relative paths: the directory of the file being evaluated, then the config's library paths
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Imports {
+    pub dir: PathBuf,
+    pub library_paths: Vec<PathBuf>
+}
+
+impl Imports {
+    // stdin is read from the current directory
+    pub fn new(file: &Path, library_paths: Vec<PathBuf>) -> Imports {
+        let dir = file.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        Imports { dir: dir.to_path_buf(), library_paths }
+    }
+}
+
+fn builtins(imports: &Rc<Imports>) -> [Builtin; 7] {
+    [
+        Builtin::ShellEscape,
+        Builtin::FromJson,
+        Builtin::FromToml,
+        Builtin::FromYaml,
+        Builtin::ImportJson(imports.clone()),
+        Builtin::ImportToml(imports.clone()),
+        Builtin::ImportYaml(imports.clone())
+    ]
+}
+
+impl Builtin {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Builtin::ShellEscape => "shellEscape",
+            Builtin::FromJson => "fromJSON",
+            Builtin::FromToml => "fromTOML",
+            Builtin::FromYaml => "fromYAML",
+            Builtin::ImportJson(_) => "importJSON",
+            Builtin::ImportToml(_) => "importTOML",
+            Builtin::ImportYaml(_) => "importYAML"
+        }
+    }
+
+    // the builtin of that name that parses text, whose result only depends on the argument, so the typechecker can run it
+    // on a literal argument to learn the result's type. The import builtins aren't included: running them would read files
+    // while typechecking, so what they read has type Any until it's evaluated
+    pub fn parser(name: &str) -> Option<Builtin> {
+        [Builtin::FromJson, Builtin::FromToml, Builtin::FromYaml].into_iter().find(|b| b.name() == name)
+    }
+
+    pub fn t(&self) -> Type {
+        match self {
+            Builtin::ShellEscape => Type::Function(Box::new(Type::Text), Box::new(Type::Text)),
+            _ => Type::Function(Box::new(Type::Text), Box::new(Type::Any))
+        }
+    }
+
+    pub fn apply(&self, arg: Value) -> Result<Value, String> {
+        match (self, arg) {
+            (Builtin::ShellEscape, Value::Text(t)) => Ok(Value::Text(quote(&t, Shell::Bash))),
+            (Builtin::FromJson, Value::Text(t)) => input::from_json(&t),
+            (Builtin::FromToml, Value::Text(t)) => input::from_toml(&t),
+            (Builtin::FromYaml, Value::Text(t)) => input::from_yaml(&t),
+            (Builtin::ImportJson(imports), Value::Text(path)) => input::from_json(&read(&path, imports)?).map_err(|e| format!("{path}: {e}")),
+            (Builtin::ImportToml(imports), Value::Text(path)) => input::from_toml(&read(&path, imports)?).map_err(|e| format!("{path}: {e}")),
+            (Builtin::ImportYaml(imports), Value::Text(path)) => input::from_yaml(&read(&path, imports)?).map_err(|e| format!("{path}: {e}")),
+            (b, arg) => Err(format!("Builtin {} can't be applied to {}", b.name(), value_source(&arg)))
+        }
+    }
+}
+
+// a leading ~ is the home directory, like in a shell
+fn read(path: &str, imports: &Imports) -> Result<String, String> {
+    let expanded = match (path.strip_prefix("~/"), std::env::var("HOME")) {
+        (Some(rest), Ok(home)) => format!("{home}/{rest}"),
+        _ => path.to_string()
+    };
+    let expanded = Path::new(&expanded);
+    let file = if expanded.is_relative() {
+        std::iter::once(&imports.dir).chain(&imports.library_paths).map(|dir| dir.join(expanded))
+            .find(|p| p.exists()).unwrap_or_else(|| imports.dir.join(expanded))
+    } else {
+        expanded.to_path_buf()
+    };
+    std::fs::read_to_string(file).map_err(|e| format!("Cannot read {path}: {e}"))
+}
+
+pub fn types() -> HashMap<Ident, Type> {
+    builtins(&Rc::default()).iter().map(|b| (b.name().to_string(), b.t())).collect()
+}
+
+pub fn values(imports: Imports) -> HashMap<Ident, Value> {
+    builtins(&Rc::new(imports)).into_iter().map(|b| (b.name().to_string(), Value::Builtin(b))).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch;
+
+    fn import(path: &str, imports: Imports) -> Result<Value, String> {
+        Builtin::ImportJson(Rc::new(imports)).apply(Value::Text(path.to_string()))
+    }
+
+    #[test]
+    fn imports_are_relative_to_the_importing_file() {
+        let dir = scratch::dir("builtins", "relative");
+        std::fs::write(dir.join("data.json"), "1").unwrap();
+        assert_eq!(import("data.json", Imports::new(&dir.join("main.conf"), Vec::new())), Ok(Value::Int(1)));
+        assert!(import("data.json", Imports::new(Path::new("main.conf"), Vec::new())).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn the_importing_files_directory_comes_before_the_library_paths() {
+        let dir = scratch::dir("builtins", "library");
+        std::fs::create_dir_all(dir.join("lib")).unwrap();
+        std::fs::write(dir.join("lib/data.json"), "1").unwrap();
+        std::fs::write(dir.join("lib/only.json"), "2").unwrap();
+        std::fs::write(dir.join("data.json"), "3").unwrap();
+        let imports = || Imports { dir: dir.clone(), library_paths: vec![dir.join("lib")] };
+        assert_eq!(import("data.json", imports()), Ok(Value::Int(3)));
+        assert_eq!(import("only.json", imports()), Ok(Value::Int(2)));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn typechecking_doesnt_read_imports() {
+        let typecheck = |source: &str| crate::typechecker::typecheck(&crate::parser::parse(source).unwrap(), &types()).map(|e| e.t);
+        assert_eq!(typecheck(r#"importJSON "does-not-exist.json""#).map_err(|d| d.message), Ok(Type::Any));
+        assert_eq!(typecheck(r#"fromJSON "1""#).map_err(|d| d.message), Ok(Type::Natural));
+    }
+
+    #[test]
+    fn arguments_of_the_wrong_type_are_written_as_source() {
+        let error = Builtin::ImportJson(Rc::new(Imports::default())).apply(Value::List(vec![Value::Int(1)]));
+        assert_eq!(error, Err("Builtin importJSON can't be applied to [1]".to_string()));
+    }
+}
@@ -1,28 +1,134 @@
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
 // use clap_complete::{generate, Generator, Shell};
 
 #[derive(Parser)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, arg_required_else_help = true)]
 pub struct Cli {
 
+    /// The config directory. By default it's $CONFIGURATOR_DIR, or configurator in
+    /// $XDG_CONFIG_HOME or ~/.config, or the closest directory above this one with a configurator.toml
+    #[arg(long, global = true)]
+    pub config_dir: Option<PathBuf>,
+
     #[command(subcommand)]
-    command: Option<Commands>,
+    pub command: Commands,
 }
 
 #[derive(Subcommand)]
-enum Commands {
+pub enum Commands {
     /// Generate a default config file
-    Init {},
+    Init {
+        /// Make the config directory a git repository
+        #[arg(long)]
+        git: bool,
+        /// Fill in settings for the tools that are installed
+        #[arg(long)]
+        detect: bool,
+        /// Replace an existing config
+        #[arg(long)]
+        force: bool,
+    },
     /// Check that the config file is valid
-    Validate {},
+    ///
+    /// Data it imports is checked against the type it's declared with, which
+    /// is how a config gives the schema of a file.
+    ///
+    /// Exits with 3 if there are syntax errors, 4 if there are type errors,
+    /// and 5 if evaluating it fails or an assertion doesn't hold
+    Validate {
+        /// The file to check, or - to read it from stdin. By default it's the config file
+        file: Option<PathBuf>,
+        /// How to print the problems that are found
+        #[arg(long, value_enum, default_value_t = DiagnosticFormat::Text)]
+        format: DiagnosticFormat,
+    },
     /// Run the input file (or config file) and pretty print the result
-    Eval {},
+    Eval {
+        /// The file to run, or - to read it from stdin. By default it's the config file
+        file: Option<PathBuf>,
+        /// The format to print the result in. By default it's the first output in the config's manifest, or JSON
+        #[arg(short, long, value_enum)]
+        output: Option<OutputFormat>,
+        /// Print JSON on a single line
+        #[arg(long)]
+        compact: bool,
+        /// Sort record fields by key instead of keeping the order they were written in
+        #[arg(long)]
+        sort_keys: bool,
+    },
+    /// Convert a JSON, TOML or YAML file into source code, with a type describing it
+    Convert {
+        /// The file to convert. Its format is chosen by its extension
+        file: PathBuf,
+        /// The name of the generated type. By default it is based on the file name
+        #[arg(long)]
+        name: Option<String>,
+    },
     /// Format the config file nicely
-    Format {},
+    Format {
+        /// The file to format, in place
+        file: Option<PathBuf>,
+        /// Don't change the file, but fail if it isn't already formatted
+        #[arg(long)]
+        check: bool,
+        /// The line width to fit the code into
+        #[arg(long, default_value_t = 80)]
+        width: usize,
+    },
+    /// Set a field in the config file, e.g. `set git.userName '"Alice"'`
+    Set {
+        /// The field to set, as a dotted path through the records in the config.
+        /// Keys that aren't names are quoted, like `aliases."ls.all"`
+        path: String,
+        /// The new value, as source code
+        value: String,
+        /// The file to edit
+        #[arg(long)]
+        file: Option<PathBuf>,
+    },
+    /// Print the changes to the system that the config asks for, in the order they would be made
+    Plan {
+        /// The file to run, or - to read it from stdin. By default it's the config file
+        file: Option<PathBuf>,
+    },
     /// Run the config and apply it to the system
-    Apply {},
+    Apply {
+        /// The file to run. By default it's the config file
+        file: Option<PathBuf>,
+        /// Show what would change, without changing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
     /// Go back to the state of the system before running Apply
-    Revert {},
+    Revert {
+        /// The generation to go back to. By default it's the one before the last apply, and 0 undoes every apply
+        #[arg(long)]
+        to: Option<u64>,
+        /// Show what would change, without changing anything
+        #[arg(long)]
+        dry_run: bool,
+        /// Revert files even if they were changed by hand since they were applied
+        #[arg(long)]
+        force: bool,
+    },
+    /// List the generations Apply has made, oldest first
+    Generations {},
+    /// Show how the files that were applied differ between two generations
+    Diff {
+        /// The older generation
+        from: u64,
+        /// The newer generation
+        to: u64,
+    },
+    /// Delete old generations, which can't be reverted afterwards
+    ///
+    /// The generation that's applied now is always kept
+    Gc {
+        /// How many of the newest generations to keep
+        #[arg(long)]
+        keep: usize,
+    },
     /// Pull the latest version of config from user git repo
     Pull {},
     /// Push the current version of config to the user git repo
@@ -31,12 +137,40 @@ enum Commands {
     Upgrade {},
     /// Open the config in the user's editor
     Edit {},
-    /// Remove this from the user's system
-    Uninstall {},
+    /// Remove this from the user's system, undoing every apply and deleting their history
+    Uninstall {
+        /// Revert files even if they were changed by hand since they were applied
+        #[arg(long)]
+        force: bool,
+    },
     /// Generate shell completion code
     Completions {},
     /// Run git in the config directory
     Git {},
     /// cd into the config directory
     Cd {},
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// The interpreter's internal representation
+    Debug,
+    Json,
+    Yaml,
+    Toml,
+    Ini,
+    /// Aliases, environment variables, PATH and functions as a bash script
+    Bash,
+    /// Aliases, environment variables, PATH and functions as a zsh script
+    Zsh,
+    /// Aliases, environment variables, PATH and functions as a fish script
+    Fish,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum DiagnosticFormat {
+    /// Messages for people to read
+    Text,
+    /// A JSON array with the file, span, severity and code of each problem, for editors and CI
+    Json,
+}
@@ -0,0 +1,155 @@
+use crate::cli::OutputFormat;
+use clap::ValueEnum;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Where the config lives, and the manifest that describes it
+
+// the file that marks a config directory
+pub const MANIFEST_FILE: &str = "configurator.toml";
+// the file in the config directory that is evaluated, unless the manifest names another
+pub const ENTRY_FILE: &str = "config.conf";
+
+// the contents of configurator.toml. Every field is optional:
+//   entry = "config.conf"
+//   min_version = "0.1.0"
+//   library_paths = ["lib"]
+//   outputs = ["bash", "json"]
+#[derive(Debug, Clone)]
+pub struct Manifest {
+    pub entry: PathBuf,
+    // the oldest version of this program that can run the config
+    pub min_version: Option<semver::Version>,
+    // where to look for files the config uses, relative to the config directory
+    pub library_paths: Vec<PathBuf>,
+    // the formats the config is meant to be turned into
+    pub outputs: Vec<OutputFormat>
+}
+
+impl Default for Manifest {
+    fn default() -> Manifest {
+        Manifest { entry: PathBuf::from(ENTRY_FILE), min_version: None, library_paths: Vec::new(), outputs: Vec::new() }
+    }
+}
+
+impl Manifest {
+    pub fn parse(source: &str) -> Result<Manifest, String> {
+        let table: toml::Table = toml::from_str(source).map_err(|e| format!("Invalid TOML: {e}"))?;
+        let mut manifest = Manifest::default();
+        for (key, value) in table {
+            match (key.as_str(), value) {
+                ("entry", toml::Value::String(s)) => manifest.entry = PathBuf::from(s),
+                ("min_version", toml::Value::String(s)) => {
+                    let v = semver::Version::parse(&s).map_err(|e| format!("min_version {s:?} is not a valid version: {e}"))?;
+                    manifest.min_version = Some(v);
+                },
+                ("library_paths", toml::Value::Array(paths)) => manifest.library_paths = strings(&key, paths)?.into_iter().map(PathBuf::from).collect(),
+                ("outputs", toml::Value::Array(outputs)) => {
+                    manifest.outputs = strings(&key, outputs)?.iter()
+                        .map(|o| OutputFormat::from_str(o, true).map_err(|_| format!("{o:?} is not an output format")))
+                        .collect::<Result<Vec<OutputFormat>, String>>()?;
+                },
+                ("entry" | "min_version", _) => return Err(format!("{key} must be a string")),
+                ("library_paths" | "outputs", _) => return Err(format!("{key} must be a list of strings")),
+                _ => return Err(format!("Unknown setting {key}"))
+            }
+        }
+        Ok(manifest)
+    }
+
+    // a new config needs at least the version that made it
+    pub fn starter() -> String {
+        format!("entry = {ENTRY_FILE:?}\nmin_version = {:?}\n", env!("CARGO_PKG_VERSION"))
+    }
+}
+
+fn strings(key: &str, values: Vec<toml::Value>) -> Result<Vec<String>, String> {
+    values.into_iter().map(|v| match v {
+        toml::Value::String(s) => Ok(s),
+        _ => Err(format!("{key} must be a list of strings"))
+    }).collect()
+}
+
+// a config directory and its manifest, which every command that works on the config shares
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub dir: PathBuf,
+    pub manifest: Manifest
+}
+
+impl Config {
+    pub fn load(dir: PathBuf) -> Result<Config, String> {
+        let path = dir.join(MANIFEST_FILE);
+        // a directory without a manifest uses the defaults
+        let manifest = match fs::read_to_string(&path) {
+            Ok(source) => Manifest::parse(&source).map_err(|e| format!("{}: {e}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Manifest::default(),
+            Err(e) => return Err(format!("Cannot read {}: {e}", path.display()))
+        };
+        if let Some(min) = &manifest.min_version {
+            let current = semver::Version::parse(env!("CARGO_PKG_VERSION")).map_err(|e| e.to_string())?;
+            if current < *min {
+                return Err(format!("The config in {} needs version {min} or newer, but this is version {current}", dir.display()));
+            }
+        }
+        Ok(Config { dir, manifest })
+    }
+
+    pub fn entry(&self) -> PathBuf {
+        self.dir.join(&self.manifest.entry)
+    }
+
+    pub fn library_paths(&self) -> Vec<PathBuf> {
+        self.manifest.library_paths.iter().map(|p| self.dir.join(p)).collect()
+    }
+}
+
+// finds the config directory, in order: the one given on the command line, $CONFIGURATOR_DIR,
+// configurator in $XDG_CONFIG_HOME (or ~/.config) if it exists, and the closest directory
+// above the current one that has a manifest
+pub fn find(flag: Option<PathBuf>) -> Result<Config, String> {
+    if let Some(dir) = flag.or_else(|| var("CONFIGURATOR_DIR")) {
+        return Config::load(dir);
+    }
+    if let Some(dir) = xdg_dir().filter(|dir| dir.is_dir()) {
+        return Config::load(dir);
+    }
+    let cwd = std::env::current_dir().map_err(|e| format!("Cannot find the current directory: {e}"))?;
+    match cwd.ancestors().find(|dir| dir.join(MANIFEST_FILE).is_file()) {
+        Some(dir) => Config::load(dir.to_path_buf()),
+        None => Err("Cannot find a config directory. Make one with `init`".to_string())
+    }
+}
+
+// where init makes the config directory: the one given on the command line, or $CONFIGURATOR_DIR,
+// or configurator in $XDG_CONFIG_HOME, which defaults to ~/.config
+pub fn config_dir(flag: Option<PathBuf>) -> Result<PathBuf, String> {
+    flag.or_else(|| var("CONFIGURATOR_DIR"))
+        .or_else(xdg_dir)
+        .ok_or_else(|| "Cannot find the config directory, as neither $XDG_CONFIG_HOME nor $HOME is set".to_string())
+}
+
+// where apply keeps what it needs to undo its changes: $CONFIGURATOR_STATE_DIR, or configurator
+// in $XDG_STATE_HOME, which defaults to ~/.local/state
+pub fn state_dir() -> Result<PathBuf, String> {
+    if let Some(dir) = var("CONFIGURATOR_STATE_DIR") {
+        return Ok(dir);
+    }
+    match var("XDG_STATE_HOME").filter(|p| p.is_absolute()) {
+        Some(state) => Ok(state.join("configurator")),
+        None => var("HOME").map(|home| home.join(".local").join("state").join("configurator"))
+            .ok_or_else(|| "Cannot find the state directory, as neither $XDG_STATE_HOME nor $HOME is set".to_string())
+    }
+}
+
+fn var(name: &str) -> Option<PathBuf> {
+    std::env::var_os(name).filter(|v| !v.is_empty()).map(PathBuf::from)
+}
+
+fn xdg_dir() -> Option<PathBuf> {
+    // relative paths in XDG_CONFIG_HOME are invalid and should be ignored
+    match var("XDG_CONFIG_HOME").filter(|p| p.is_absolute()) {
+        Some(config) => Some(config.join("configurator")),
+        None => var("HOME").map(|home| Path::new(&home).join(".config").join("configurator"))
+    }
+}
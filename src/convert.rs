@@ -0,0 +1,185 @@
+use crate::ast::{Expr, ExprKind, Type, Value, Color, RecordMap, Span};
+use crate::printer;
+
+// Turns data read from another format into source code, with a type alias describing its shape:
+//   type Name = { ... }
+//
+//   let name : Name = { ... } in
+//   name
+pub fn convert(value: &Value, type_name: &str, width: usize) -> Result<String, String> {
+    if !printer::is_ident(type_name) {
+        return Err(format!("{type_name:?} is not a valid type name"));
+    }
+    let body = value_expr(value)?;
+    let t = shape(&body);
+    let binding = binding_name(type_name);
+    let source = Expr {
+        t: None,
+        span: Span::default(),
+        expr: ExprKind::TypeDecl(type_name.to_string(), t, Box::new(Expr {
+            t: None,
+            span: Span::default(),
+            expr: ExprKind::Let(
+                binding.clone(),
+                Some(Type::Ident(type_name.to_string())),
+                Box::new(body),
+                Box::new(Expr { t: None, span: Span::default(), expr: ExprKind::Ident(binding) })
+            )
+        }))
+    };
+    Ok(printer::print_expr(&source, width))
+}
+
+// a type name from a file name, e.g. starship.toml gives Starship and my-settings.json gives MySettings
+pub fn type_name(file_stem: &str) -> String {
+    let name: String = file_stem
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(c) => c.to_ascii_uppercase().to_string() + chars.as_str(),
+                None => String::new()
+            }
+        })
+        .collect();
+    // builtin type names can't be redeclared
+    const BUILTIN_TYPES: [&str; 12] = ["Bool", "Text", "Number", "Natural", "Integer", "Real", "Color", "Path", "Null", "Version", "Any", "Type"];
+    if !name.starts_with(|c: char| c.is_ascii_alphabetic()) || BUILTIN_TYPES.contains(&name.as_str()) {
+        format!("Config{name}")
+    } else {
+        name
+    }
+}
+
+fn binding_name(type_name: &str) -> String {
+    let mut chars = type_name.chars();
+    let name = match chars.next() {
+        Some(c) => c.to_ascii_lowercase().to_string() + chars.as_str(),
+        None => String::new()
+    };
+    if printer::is_ident(&name) { name } else { "config".to_string() }
+}
+
+fn literal(expr: ExprKind<Expr>, t: Type) -> Expr {
+    Expr { t: Some(t), span: Span::default(), expr }
+}
+
+// text that looks like a version or a color becomes a literal of that type
+pub fn value_expr(value: &Value) -> Result<Expr, String> {
+    Ok(match value {
+        Value::Text(t) => match (semver::Version::parse(t), color(t)) {
+            (Ok(v), _) => literal(ExprKind::Version(v), Type::Version),
+            (_, Some(c)) => literal(ExprKind::Color(c), Type::Color),
+            _ => literal(ExprKind::Text(t.clone()), Type::Text)
+        },
+        Value::Int(n) if *n >= 0 => literal(ExprKind::Int(*n), Type::Natural),
+        Value::Int(n) => literal(ExprKind::Int(*n), Type::Integer),
+        Value::Float(n) => literal(ExprKind::Float(*n), Type::Real),
+        Value::Boolean(b) => literal(ExprKind::Boolean(*b), Type::Bool),
+        Value::Null => literal(ExprKind::Null, Type::Null),
+        Value::Color(c) => literal(ExprKind::Color(*c), Type::Color),
+        Value::Version(v) => literal(ExprKind::Version(v.clone()), Type::Version),
+        Value::List(l) => Expr {
+            t: None,
+            span: Span::default(),
+            expr: ExprKind::List(l.iter().map(value_expr).collect::<Result<Vec<Expr>, String>>()?)
+        },
+        Value::Record(hm) => {
+            let mut fields = RecordMap::new();
+            for (k, v) in hm {
+                fields.insert(k.clone(), value_expr(v)?);
+            }
+            Expr { t: None, span: Span::default(), expr: ExprKind::Record(fields) }
+        },
+        Value::Lambda(..) | Value::Builtin(_) => return Err("functions can't be converted to source code".to_string())
+    })
+}
+
+// #rgb, #rgba, #rrggbb or #rrggbbaa, like a hexcolor literal
+fn color(t: &str) -> Option<Color> {
+    let hex = t.strip_prefix('#')?;
+    if ![3, 4, 6, 8].contains(&hex.len()) || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let hex: String = if hex.len() <= 4 { hex.chars().flat_map(|c| [c, c]).collect() } else { hex.to_string() };
+    let channel = |i: usize| hex.get(i..i + 2).and_then(|c| u8::from_str_radix(c, 16).ok());
+    Some(Color { r: channel(0)?, g: channel(2)?, b: channel(4)?, a: channel(6).unwrap_or(255) })
+}
+
+// the type of a converted value. Unlike the typechecker, which only keeps the fields that all
+// the records in a list have in common, this keeps every field, so it describes all of the data
+fn shape(e: &Expr) -> Type {
+    match &e.expr {
+        ExprKind::List(l) => {
+            let mut alternatives: Vec<Type> = Vec::new();
+            for t in l.iter().map(shape) {
+                match alternatives.iter().position(|a| merge(a, &t).is_some()) {
+                    Some(i) => alternatives[i] = merge(&alternatives[i], &t).unwrap(),
+                    None => alternatives.push(t)
+                }
+            }
+            // null goes last, so that T | Null is written as T?
+            alternatives.sort_by_key(|t| matches!(t, Type::Null));
+            let t = alternatives.into_iter().reduce(|a, b| Type::Alternative(Box::new(a), Box::new(b)));
+            Type::List(Box::new(t.unwrap_or(Type::Any)))
+        },
+        ExprKind::Record(hm) => Type::Record(hm.iter().map(|(k, v)| (k.clone(), shape(v))).collect()),
+        _ => e.t.clone().unwrap_or(Type::Any)
+    }
+}
+
+// a type that describes values of both types, if they are similar enough to share one
+fn merge(a: &Type, b: &Type) -> Option<Type> {
+    match (a, b) {
+        (Type::Record(hm1), Type::Record(hm2)) if hm1.len() == hm2.len() && hm1.keys().all(|k| hm2.contains_key(k)) => {
+            let mut merged = RecordMap::new();
+            for (k, t) in hm1 {
+                merged.insert(k.clone(), merge(t, &hm2[k])?);
+            }
+            Some(Type::Record(merged))
+        },
+        (Type::List(a), Type::List(b)) => Some(Type::List(Box::new(merge(a, b)?))),
+        (Type::Record(_), _) | (_, Type::Record(_)) | (Type::List(_), _) | (_, Type::List(_)) => None,
+        (a, b) if a <= b => Some(b.clone()),
+        (a, b) if a >= b => Some(a.clone()),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::KeyOrder;
+    use crate::input::from_json;
+    use crate::output::json::to_json;
+
+    // the value the converted source evaluates to, after it's typechecked
+    fn evaluate(source: &str) -> Value {
+        let e = crate::parser::parse(source).unwrap();
+        let e = crate::typechecker::resolve_aliases(&e, &std::collections::HashMap::new()).unwrap();
+        crate::typechecker::typecheck(&e, &crate::builtins::types()).unwrap();
+        crate::interpreter::normalize(&e, &crate::builtins::values(Default::default()), &mut Vec::new()).unwrap()
+    }
+
+    #[test]
+    fn converted_data_evaluates_to_itself() {
+        let json = r##"{"name":"x","version":"1.2.3","color":"#ff0000","servers":[{"host":"a","port":1},{"host":"b","port":2,"backup":null}],"n":-1,"f":0.5,"empty":[],"key with spaces":{}}"##;
+        let source = convert(&from_json(json).unwrap(), "Settings", 80).unwrap();
+        assert_eq!(to_json(&evaluate(&source), false, KeyOrder::Insertion).unwrap(), json);
+    }
+
+    #[test]
+    fn the_type_keeps_every_field() {
+        let source = convert(&from_json(r#"{"servers":[{"host":"a"},{"host":"b","port":2}]}"#).unwrap(), "Settings", 80).unwrap();
+        assert!(source.contains("servers : [{ host : Text } | { host : Text, port : Natural }]"), "{source}");
+    }
+
+    #[test]
+    fn type_names_come_from_file_names() {
+        assert_eq!(type_name("starship"), "Starship");
+        assert_eq!(type_name("my-settings"), "MySettings");
+        assert_eq!(type_name("1password"), "Config1password");
+        assert_eq!(type_name("text"), "ConfigText");
+        assert!(convert(&Value::Null, "not a name", 80).is_err());
+    }
+}
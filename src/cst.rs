@@ -0,0 +1,185 @@
+use pest::Parser;
+use pest::iterators::Pair;
+use crate::ast::Span;
+use crate::parser::{GrammarParser, Rule};
+
+// A concrete syntax tree. Unlike the AST it keeps everything in the source, including whitespace,
+// comments and punctuation, so tools that rewrite files can leave the parts they don't touch alone.
+// The nodes are the rules that pest produces pairs for, and every byte between them is a token
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TokenKind {
+    Whitespace,
+    Comment,
+    // keywords and punctuation, and the text of atomic rules
+    Syntax
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Token<'a> {
+    pub kind: TokenKind,
+    pub text: &'a str,
+    pub span: Span
+}
+
+#[derive(Debug, Clone)]
+pub enum Element<'a> {
+    Node(CstNode<'a>),
+    Token(Token<'a>)
+}
+
+#[derive(Debug, Clone)]
+pub struct CstNode<'a> {
+    rule: Rule,
+    text: &'a str,
+    span: Span,
+    elements: Vec<Element<'a>>
+}
+
+// atomic rules can't contain whitespace or comments, so their text is kept as a single token.
+// a string's contents might look like a comment
+const ATOMIC: [Rule; 13] = [
+    Rule::natural, Rule::real, Rule::binary, Rule::octal, Rule::hex, Rule::hexcolor, Rule::v_extra,
+    Rule::inner, Rule::keyword, Rule::ident, Rule::user_type, Rule::not_in, Rule::in_op
+];
+
+pub fn parse(source: &str) -> Result<CstNode<'_>, Box<pest::error::Error<Rule>>> {
+    let pairs = GrammarParser::parse(Rule::file, source).map_err(Box::new)?;
+    let span = Span { start: 0, end: source.len() };
+    Ok(node(source, Rule::file, span, pairs.filter(|p| p.as_rule() != Rule::EOI).collect()))
+}
+
+fn from_pair<'a>(source: &'a str, pair: Pair<'a, Rule>) -> CstNode<'a> {
+    let span = Span { start: pair.as_span().start(), end: pair.as_span().end() };
+    if ATOMIC.contains(&pair.as_rule()) {
+        let token = Token { kind: TokenKind::Syntax, text: pair.as_str(), span };
+        return CstNode { rule: pair.as_rule(), text: pair.as_str(), span, elements: vec![Element::Token(token)] };
+    }
+    node(source, pair.as_rule(), span, pair.into_inner().collect())
+}
+
+// the children of a node, with the text between them split into tokens
+fn node<'a>(source: &'a str, rule: Rule, span: Span, children: Vec<Pair<'a, Rule>>) -> CstNode<'a> {
+    let mut elements = Vec::new();
+    let mut pos = span.start;
+    for child in children {
+        let start = child.as_span().start();
+        tokens(source, pos, start, &mut elements);
+        pos = child.as_span().end();
+        elements.push(Element::Node(from_pair(source, child)));
+    }
+    tokens(source, pos, span.end, &mut elements);
+    CstNode { rule, text: &source[span.start..span.end], span, elements }
+}
+
+fn tokens<'a>(source: &'a str, start: usize, end: usize, elements: &mut Vec<Element<'a>>) {
+    let gap = &source[start..end];
+    let mut pos = 0;
+    while pos < gap.len() {
+        let rest = &gap[pos..];
+        let (kind, len) = if rest.starts_with("//") {
+            (TokenKind::Comment, rest.find('\n').unwrap_or(rest.len()))
+        } else if let Some(body) = rest.strip_prefix("/*") {
+            (TokenKind::Comment, body.find("*/").map_or(rest.len(), |n| n + 4))
+        } else if rest.starts_with(char::is_whitespace) {
+            (TokenKind::Whitespace, rest.find(|c: char| !c.is_whitespace()).unwrap_or(rest.len()))
+        } else {
+            // a word, or a run of punctuation
+            let word = rest.starts_with(|c: char| c.is_alphanumeric() || c == '_');
+            let len = rest.char_indices()
+                .find(|(i, c)| c.is_whitespace() || (c.is_alphanumeric() || *c == '_') != word || rest[*i..].starts_with("//") || rest[*i..].starts_with("/*"))
+                .map_or(rest.len(), |(i, _)| i);
+            (TokenKind::Syntax, len)
+        };
+        let span = Span { start: start + pos, end: start + pos + len };
+        elements.push(Element::Token(Token { kind, text: &rest[..len], span }));
+        pos += len;
+    }
+}
+
+impl<'a> CstNode<'a> {
+    pub fn as_rule(&self) -> Rule {
+        self.rule
+    }
+
+    pub fn as_str(&self) -> &'a str {
+        self.text
+    }
+
+    pub fn span(&self) -> Span {
+        self.span
+    }
+
+    // the child nodes, without the tokens between them, like pest's Pair::into_inner
+    pub fn children(&self) -> impl Iterator<Item = &CstNode<'a>> {
+        self.elements.iter().filter_map(|e| match e {
+            Element::Node(n) => Some(n),
+            Element::Token(_) => None
+        })
+    }
+
+    // every token in the node, in order
+    pub fn tokens(&self) -> Vec<Token<'a>> {
+        let mut tokens = Vec::new();
+        for e in &self.elements {
+            match e {
+                Element::Node(n) => tokens.extend(n.tokens()),
+                Element::Token(t) => tokens.push(*t)
+            }
+        }
+        tokens
+    }
+
+    pub fn comments(&self) -> Vec<Token<'a>> {
+        self.tokens().into_iter().filter(|t| t.kind == TokenKind::Comment).collect()
+    }
+
+    // the source text, rebuilt from the tokens. This is always exactly the text that was parsed
+    pub fn to_source(&self) -> String {
+        self.tokens().iter().map(|t| t.text).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(source: &str) {
+        assert_eq!(parse(source).unwrap().to_source(), source);
+    }
+
+    #[test]
+    fn the_examples_round_trip() {
+        round_trip(include_str!("../testfile"));
+        round_trip(&crate::init::starter(false).unwrap());
+    }
+
+    #[test]
+    fn comments_and_whitespace_are_tokens() {
+        let source = "// a\n{ b = /* c */ 1, d = \"// e\" } // f\n";
+        let tree = parse(source).unwrap();
+        round_trip(source);
+        let comments: Vec<&str> = tree.comments().iter().map(|t| t.text).collect();
+        assert_eq!(comments, ["// a", "/* c */", "// f"]);
+        for token in tree.tokens() {
+            assert_eq!(&source[token.span.start..token.span.end], token.text);
+            assert_eq!(token.kind == TokenKind::Whitespace, token.text.trim().is_empty());
+        }
+    }
+
+    #[test]
+    fn atomic_rules_are_single_tokens() {
+        let tree = parse("\"a // b\" + 0x1F").unwrap();
+        let syntax: Vec<&str> = tree.tokens().iter().filter(|t| t.kind == TokenKind::Syntax).map(|t| t.text).collect();
+        assert_eq!(syntax, ["\"", "a // b", "\"", "+", "0x1F"]);
+    }
+
+    #[test]
+    fn children_are_the_rules() {
+        let tree = parse("type T = Text\n[1, x]").unwrap();
+        let rules: Vec<Rule> = tree.children().map(|c| c.as_rule()).collect();
+        assert_eq!(rules, [Rule::type_decl, Rule::binop_expr]);
+        let list = tree.children().nth(1).unwrap().children().next().unwrap();
+        assert_eq!((list.as_rule(), list.as_str()), (Rule::list, "[1, x]"));
+    }
+}
@@ -0,0 +1,124 @@
+use crate::cst::{self, CstNode};
+use crate::parser::{self, GrammarParser, Rule};
+use pest::Parser;
+use crate::printer::key_source;
+use std::collections::HashMap;
+
+// Editing source files. Only the text of the edited field changes,
+// so the formatting and comments in the rest of the file are kept
+
+// sets the field at the path in the record the file evaluates to, adding it (and any records on the way) if it's missing.
+// the value is source code, and the result has to be checked, as the new value might not fit
+pub fn set(source: &str, path: &[&str], value: &str) -> Result<String, String> {
+    // anything more than one expression could add fields or end the record early
+    GrammarParser::parse(Rule::value_only, value).map_err(|e| format!("The value must be a single expression:\n{e}"))?;
+    let tree = cst::parse(source).map_err(|e| e.to_string())?;
+    let mut lets = HashMap::new();
+    let mut record = result(&tree, &mut lets);
+    for (i, key) in path.iter().enumerate() {
+        if record.as_rule() != Rule::record {
+            return Err(match i {
+                0 => "The file doesn't evaluate to a record that can be edited".to_string(),
+                _ => format!("{} isn't a record that can be edited", path[..i].iter().map(|k| key_source(k)).collect::<Vec<String>>().join("."))
+            });
+        }
+        let field = record.children().find(|pair| pair.children().next().and_then(|k| parser::parse_key(k).ok()).as_deref() == Some(key));
+        match field {
+            Some(pair) => {
+                let v = pair.children().nth(1).unwrap();
+                if i + 1 == path.len() {
+                    return Ok(splice(source, v.span().start, v.span().end, value));
+                }
+                record = result(v, &mut lets);
+            },
+            None => return insert(source, record, &path[i..], value)
+        }
+    }
+    Err("The path to set is empty".to_string())
+}
+
+// the expression a node evaluates to, looking through lets, assertions, parentheses and let bound identifiers
+fn result<'t, 'a>(node: &'t CstNode<'a>, lets: &mut HashMap<&'a str, &'t CstNode<'a>>) -> &'t CstNode<'a> {
+    match node.as_rule() {
+        Rule::file | Rule::assert_expr => result(node.children().last().unwrap(), lets),
+        Rule::paren_expr => result(node.children().next().unwrap(), lets),
+        // every expression without a let, if or assert is a binop_expr, usually with a single operand
+        Rule::binop_expr if node.children().count() == 1 => result(node.children().next().unwrap(), lets),
+        Rule::let_expr => {
+            let binding = node.children().next().unwrap();
+            let parts: Vec<&CstNode> = binding.children().collect();
+            // the name, the type if there is one, the value and the body
+            lets.insert(parts[0].as_str(), parts[parts.len() - 2]);
+            result(parts[parts.len() - 1], lets)
+        },
+        Rule::ident => match lets.get(node.as_str()) {
+            Some(value) => result(value, lets),
+            None => node
+        },
+        _ => node
+    }
+}
+
+fn splice(source: &str, start: usize, end: usize, text: &str) -> String {
+    format!("{}{text}{}", &source[..start], &source[end..])
+}
+
+// adds a field to a record, laid out like the fields already in it
+fn insert(source: &str, record: &CstNode, path: &[&str], value: &str) -> Result<String, String> {
+    // missing records on the way are created too
+    let mut field = value.to_string();
+    for key in path[1..].iter().rev() {
+        field = format!("{{ {} = {field} }}", key_source(key));
+    }
+    let field = format!("{} = {field}", key_source(path[0]));
+    let span = record.span();
+    let last = match record.children().last() {
+        Some(last) => last.span(),
+        // an empty record might still have comments in it, so only the closing brace is moved along
+        None if !record.comments().is_empty() => return Ok(splice(source, span.end - 1, span.end - 1, &format!("{field} "))),
+        None => return Ok(splice(source, span.start, span.end, &format!("{{ {field} }}")))
+    };
+    let line_start = source[..last.start].rfind('\n').map_or(0, |i| i + 1);
+    let indent = &source[line_start..last.start];
+    if !indent.chars().all(char::is_whitespace) || !source[span.start..span.end].contains('\n') {
+        // the fields are on one line
+        return Ok(splice(source, last.end, last.end, &format!(", {field}")));
+    }
+    // the new field goes on its own line, after any comment on the line of the last field
+    let after = &source[last.end..span.end];
+    let line_end = last.end + after.find('\n').unwrap_or(after.len() - 1);
+    let trailing_comma = after.trim_start().starts_with(',');
+    let edited = splice(source, line_end, line_end, &format!("\n{indent}{field}{}", if trailing_comma { "," } else { "" }));
+    Ok(if trailing_comma { edited } else { splice(&edited, last.end, last.end, ",") })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set_path(source: &str, path: &str, value: &str) -> Result<String, String> {
+        let keys = parser::parse_path(path)?;
+        set(source, &keys.iter().map(String::as_str).collect::<Vec<&str>>(), value)
+    }
+
+    #[test]
+    fn sets_and_adds_fields() {
+        assert_eq!(set_path("{ a = 1 } // kept", "a", "2").unwrap(), "{ a = 2 } // kept");
+        assert_eq!(set_path("{ a = 1 }", "b.c", "true").unwrap(), "{ a = 1, b = { c = true } }");
+    }
+
+    #[test]
+    fn values_must_be_one_expression() {
+        assert!(set_path("{ a = 1 }", "a", "1, b = 2").is_err());
+        assert!(set_path("{ a = 1 }", "a", "1 }").is_err());
+        assert_eq!(set_path("{ a = 1 }", "a", "1 + 2").unwrap(), "{ a = 1 + 2 }");
+    }
+
+    #[test]
+    fn quoted_path_segments() {
+        assert_eq!(parser::parse_path("a.\"b.c\".d").unwrap(), ["a", "b.c", "d"]);
+        assert_eq!(set_path("{ \"b.c\" = { d = 2 } }", "\"b.c\".d", "3").unwrap(), "{ \"b.c\" = { d = 3 } }");
+        assert_eq!(set_path("{}", "\"not a name\"", "1").unwrap(), "{ \"not a name\" = 1 }");
+        assert!(parser::parse_path("a..b").is_err());
+    }
+}
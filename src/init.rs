@@ -0,0 +1,68 @@
+use std::os::unix::fs::PermissionsExt;
+use crate::ast::{Value, RecordMap};
+use crate::{convert, printer};
+
+// The starter config written by init
+
+// tools that there's something to configure for when they're installed: the program,
+// the field of the config the setting goes in, and the setting
+const KNOWN_TOOLS: [(&str, &str, &str, &str); 6] = [
+    ("git", "aliases", "gs", "git status"),
+    ("git", "aliases", "gd", "git diff"),
+    ("exa", "aliases", "ls", "exa"),
+    ("nvim", "env", "EDITOR", "nvim"),
+    ("vim", "env", "EDITOR", "vim"),
+    ("nano", "env", "EDITOR", "nano")
+];
+
+// the entry file of a new config. If detect is set, it has settings for the tools on the PATH
+pub fn starter(detect: bool) -> Result<String, String> {
+    let mut fields: RecordMap<RecordMap<Value>> = RecordMap::new();
+    fields.insert("aliases".to_string(), RecordMap::new());
+    fields.insert("env".to_string(), RecordMap::new());
+    if detect {
+        for (program, field, key, setting) in KNOWN_TOOLS {
+            let settings = fields.entry(field.to_string()).or_default();
+            // earlier tools are preferred, e.g. for the editor
+            if !settings.contains_key(key) && installed(program) {
+                settings.insert(key.to_string(), Value::Text(setting.to_string()));
+            }
+        }
+    }
+    let mut config: RecordMap<Value> = fields.into_iter().map(|(k, v)| (k, Value::Record(v))).collect();
+    config.insert("path".to_string(), Value::List(Vec::new()));
+    let body = printer::print_expr(&convert::value_expr(&Value::Record(config))?, 80);
+    Ok(format!("// Run this with `eval`, or turn it into shell configuration with `eval --output bash`\n{body}"))
+}
+
+fn installed(program: &str) -> bool {
+    installed_in(program, &std::env::var_os("PATH").unwrap_or_default())
+}
+
+// like a shell, only executable files count
+fn installed_in(program: &str, path: &std::ffi::OsStr) -> bool {
+    std::env::split_paths(path).any(|dir| {
+        let file = dir.join(program);
+        file.is_file() && file.metadata().is_ok_and(|m| m.permissions().mode() & 0o111 != 0)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch;
+
+    #[test]
+    fn only_executables_are_installed() {
+        let dir = scratch::dir("init", "installed");
+        std::fs::write(dir.join("program"), "").unwrap();
+        std::fs::write(dir.join("data"), "").unwrap();
+        std::fs::set_permissions(dir.join("program"), std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::fs::set_permissions(dir.join("data"), std::fs::Permissions::from_mode(0o644)).unwrap();
+        let path = std::env::join_paths([&dir]).unwrap();
+        assert!(installed_in("program", &path));
+        assert!(!installed_in("data", &path));
+        assert!(!installed_in("missing", &path));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,108 @@
+use crate::ast::{Value, RecordMap};
+
+// Converting data from other formats into values
+
+pub fn from_json(source: &str) -> Result<Value, String> {
+    let json: serde_json::Value = serde_json::from_str(source).map_err(|e| format!("Invalid JSON: {e}"))?;
+    Ok(json_value(json))
+}
+
+fn json_value(json: serde_json::Value) -> Value {
+    use serde_json::Value as J;
+    match json {
+        J::Null => Value::Null,
+        J::Bool(b) => Value::Boolean(b),
+        // integers too large for an i64 become floats
+        J::Number(n) => match n.as_i64() {
+            Some(i) => Value::Int(i),
+            None => Value::Float(n.as_f64().unwrap_or(f64::NAN))
+        },
+        J::String(s) => Value::Text(s),
+        J::Array(l) => Value::List(l.into_iter().map(json_value).collect()),
+        J::Object(hm) => Value::Record(hm.into_iter().map(|(k, v)| (k, json_value(v))).collect())
+    }
+}
+
+pub fn from_toml(source: &str) -> Result<Value, String> {
+    let table: toml::Table = toml::from_str(source).map_err(|e| format!("Invalid TOML: {e}"))?;
+    Ok(toml_value(toml::Value::Table(table)))
+}
+
+fn toml_value(toml: toml::Value) -> Value {
+    use toml::Value as T;
+    match toml {
+        T::Boolean(b) => Value::Boolean(b),
+        T::Integer(i) => Value::Int(i),
+        T::Float(f) => Value::Float(f),
+        T::String(s) => Value::Text(s),
+        // there are no dates in the language yet
+        T::Datetime(d) => Value::Text(d.to_string()),
+        T::Array(l) => Value::List(l.into_iter().map(toml_value).collect()),
+        T::Table(hm) => Value::Record(hm.into_iter().map(|(k, v)| (k, toml_value(v))).collect())
+    }
+}
+
+pub fn from_yaml(source: &str) -> Result<Value, String> {
+    let yaml: serde_norway::Value = serde_norway::from_str(source).map_err(|e| format!("Invalid YAML: {e}"))?;
+    yaml_value(yaml)
+}
+
+fn yaml_value(yaml: serde_norway::Value) -> Result<Value, String> {
+    use serde_norway::Value as Y;
+    match yaml {
+        Y::Null => Ok(Value::Null),
+        Y::Bool(b) => Ok(Value::Boolean(b)),
+        Y::Number(n) => match n.as_i64() {
+            Some(i) => Ok(Value::Int(i)),
+            None => Ok(Value::Float(n.as_f64().unwrap_or(f64::NAN)))
+        },
+        Y::String(s) => Ok(Value::Text(s)),
+        Y::Sequence(l) => Ok(Value::List(l.into_iter().map(yaml_value).collect::<Result<Vec<Value>, String>>()?)),
+        Y::Mapping(hm) => {
+            let mut record = RecordMap::new();
+            for (k, v) in hm {
+                // record keys are text, so scalar keys are converted and anything else is rejected
+                let key = match k {
+                    Y::String(s) => s,
+                    Y::Bool(b) => b.to_string(),
+                    Y::Number(n) => n.to_string(),
+                    Y::Null => "null".to_string(),
+                    k => return Err(format!("YAML mapping keys must be scalars to become record fields, found {k:?}"))
+                };
+                record.insert(key, yaml_value(v)?);
+            }
+            Ok(Value::Record(record))
+        },
+        // tags have no meaning in the language, so only the tagged value is kept
+        Y::Tagged(tagged) => yaml_value(tagged.value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_keeps_key_order_and_large_integers_become_floats() {
+        let value = from_json(r#"{ "b": 1, "a": 18446744073709551615 }"#).unwrap();
+        let Value::Record(hm) = value else { panic!() };
+        assert_eq!(hm.keys().collect::<Vec<&String>>(), ["b", "a"]);
+        assert_eq!(hm["a"], Value::Float(18446744073709551615.0));
+        assert!(from_json("{").unwrap_err().starts_with("Invalid JSON: "));
+    }
+
+    #[test]
+    fn toml_dates_become_text() {
+        let value = from_toml("a = 1979-05-27T07:32:00Z\nb = [1.5]\n").unwrap();
+        assert_eq!(value, from_json(r#"{ "a": "1979-05-27T07:32:00Z", "b": [1.5] }"#).unwrap());
+        assert!(from_toml("a =").unwrap_err().starts_with("Invalid TOML: "));
+    }
+
+    #[test]
+    fn yaml_keys_become_text_and_tags_are_dropped() {
+        let value = from_yaml("1: a\ntrue: !tag b\nnull: ~\n").unwrap();
+        assert_eq!(value, from_json(r#"{ "1": "a", "true": "b", "null": null }"#).unwrap());
+        assert!(from_yaml("[a]: b\n").unwrap_err().starts_with("YAML mapping keys must be scalars"));
+        assert!(from_yaml("a: [").unwrap_err().starts_with("Invalid YAML: "));
+    }
+}
@@ -1,82 +1,177 @@
-use crate::ast::{TypedExpr, Expr, Ident, Value, ExprKind, Bop};
+use crate::ast::{Diagnostic, RecordMap, Expr, Ident, Value, ExprKind, Bop, Type, Span};
+use crate::typechecker::check_value;
+use crate::printer::value_source;
 use std::collections::HashMap;
 
-// reduce the AST to its simplest form
-pub fn normalize(expr: &Expr, bindings: &HashMap<Ident,Value>) -> Result<Value, String> {
+// an assertion or refinement that didn't hold.
+// evaluation carries on after a failure so that all of them can be reported at once
+#[derive(Debug, Clone)]
+pub struct AssertionFailure {
+    pub message: String,
+    pub span: Span
+}
+
+impl AssertionFailure {
+    pub fn into_diagnostic(self) -> Diagnostic {
+        Diagnostic::new(self.span, &format!("assertion failed: {}", self.message), None)
+    }
+}
+
+// reduce the AST to its simplest form. Errors are located at the innermost expression they come from
+pub fn normalize(expr: &Expr, bindings: &HashMap<Ident,Value>, failures: &mut Vec<AssertionFailure>) -> Result<Value, Diagnostic> {
+    normalize_expr(expr, bindings, failures).map_err(|e| e.or_at(expr.span))
+}
+
+fn normalize_expr(expr: &Expr, bindings: &HashMap<Ident,Value>, failures: &mut Vec<AssertionFailure>) -> Result<Value, Diagnostic> {
     match &expr.expr {
         ExprKind::App(e1, e2) => {
             // TODO: call by value? call by name? call by something else?
-            let ne1 = normalize(&*e1, bindings)?;
-            if let Value::Lambda(id, e) = ne1 {
-                let ne2 = normalize(&*e2, bindings)?;
-                let mut new_bindings = bindings.clone();
-                new_bindings.insert(id, ne2);
-                normalize(&e, &new_bindings)
-            } else {
-                Err(format!("Expression {ne1:#?} is not a lambda"))
+            let ne1 = normalize(e1, bindings, failures)?;
+            match ne1 {
+                Value::Lambda(id, t, e) => {
+                    let ne2 = normalize(e2, bindings, failures)?;
+                    if let Some(t) = &t {
+                        check_value(&ne2, t, &id).map_err(|e| Diagnostic::from(e).or_at(e2.span))?;
+                        check_refinements(t, &ne2, bindings, failures)?;
+                    }
+                    let mut new_bindings = bindings.clone();
+                    new_bindings.insert(id, ne2);
+                    normalize(&e, &new_bindings, failures)
+                },
+                Value::Builtin(b) => Ok(b.apply(normalize(e2, bindings, failures)?)?),
+                _ => Err(format!("Expression {ne1:#?} is not a lambda").into())
             }
         },
-        ExprKind::Lambda(id, _, e) => Ok(Value::Lambda(id.clone(), *e.clone())),
-        ExprKind::Let(id, _, e1, e2) => {
-            let newe1 = normalize(&*e1, bindings)?;
+        ExprKind::Lambda(id, t, e) => Ok(Value::Lambda(id.clone(), t.clone(), e.clone())),
+        ExprKind::Let(id, t, e1, e2) => {
+            let newe1 = normalize(e1, bindings, failures)?;
+            if let Some(t) = t {
+                check_value(&newe1, t, id).map_err(|e| Diagnostic::from(e).or_at(e1.span))?;
+                check_refinements(t, &newe1, bindings, failures)?;
+            }
             let mut new_bindings = bindings.clone();
             new_bindings.insert(id.clone(), newe1);
-            normalize(&*e2, &new_bindings)
+            normalize(e2, &new_bindings, failures)
         }
         ExprKind::If(b, e1, e2) => {
-            let newb = normalize(&*b, bindings)?;
+            let newb = normalize(b, bindings, failures)?;
             if let Value::Boolean(bo) = newb {
                 if bo {
-                    normalize(&*e1, bindings)
+                    normalize(e1, bindings, failures)
                 } else {
-                    normalize(&*e2, bindings)
+                    normalize(e2, bindings, failures)
                 }
             } else {
-                Err(format!("Value {newb:#?} is not a boolean"))
+                Err(format!("Value {newb:#?} is not a boolean").into())
             }
         },
+        ExprKind::Assert(cond, message, e) => {
+            match normalize(cond, bindings, failures)? {
+                Value::Boolean(true) => (),
+                Value::Boolean(false) => {
+                    match normalize(message, bindings, failures)? {
+                        Value::Text(message) => failures.push(AssertionFailure { message, span: cond.span }),
+                        v => return Err(format!("Assertion message {v:#?} is not text").into())
+                    }
+                },
+                v => return Err(format!("Value {v:#?} is not a boolean").into())
+            }
+            normalize(e, bindings, failures)
+        },
         ExprKind::Unop(uop, e) => {
             use crate::ast::Uop;
-            let v = normalize(&*e, bindings)?;
+            let v = normalize(e, bindings, failures)?;
             match (uop, v) {
                 (Uop::Neg, Value::Float(n)) => Ok(Value::Float(-n)),
-                (Uop::Neg, Value::Int(n)) => Ok(Value::Int(-n)),
+                (Uop::Neg, Value::Int(n)) => n.checked_neg().map(Value::Int).ok_or_else(|| format!("-({n}) is too large for an integer").into()),
                 (Uop::Not, Value::Boolean(b)) => Ok(Value::Boolean(!b)),
-                _ => unreachable!()
+                // an untyped lambda's argument isn't checked until it's applied
+                (Uop::Neg, v) => Err(format!("Cannot negate {}", value_source(&v)).into()),
+                (Uop::Not, v) => Err(format!("Cannot apply ! to {}", value_source(&v)).into())
             }
         }
         ExprKind::Record(hm) => {
-            let mut reduced_hm = HashMap::new();
+            let mut reduced_hm = RecordMap::new();
             for (k,v) in hm {
-                let newv = normalize(v, bindings)?;
+                let newv = normalize(v, bindings, failures)?;
                 reduced_hm.insert(k.clone(), newv);
             }
             Ok(Value::Record(reduced_hm))
         },
         ExprKind::List(l) => {
-            let newl : Result<Vec<Value>, String> = l.iter().map(|e| normalize(e, bindings)).collect();
+            let newl : Result<Vec<Value>, Diagnostic> = l.iter().map(|e| normalize(e, bindings, failures)).collect();
             Ok(Value::List(newl?))
         },
         ExprKind::Ident(id) => {
             match bindings.get(id) {
                 Some(v) => Ok(v.clone()),
-                None => Err(format!("Identifier '{id}' is not bound"))
+                None => Err(format!("Identifier '{id}' is not bound").into())
             }
         },
         ExprKind::Text(t) => Ok(Value::Text(t.clone())),
-        ExprKind::Float(num) => Ok(Value::Float(num.clone())),
-        ExprKind::Int(num) => Ok(Value::Int(num.clone())),
-        ExprKind::Boolean(b) => Ok(Value::Boolean(b.clone())),
+        ExprKind::Float(num) => Ok(Value::Float(*num)),
+        ExprKind::Int(num) => Ok(Value::Int(*num)),
+        ExprKind::Boolean(b) => Ok(Value::Boolean(*b)),
         ExprKind::Null => Ok(Value::Null),
+        ExprKind::Color(c) => Ok(Value::Color(*c)),
+        ExprKind::Version(v) => Ok(Value::Version(v.clone())),
+        // aliases are resolved before evaluation, so only the body matters
+        ExprKind::TypeDecl(_, _, e) => normalize(e, bindings, failures),
         ExprKind::Binop(e1, bop, e2) => {
-            let ne1 = normalize(&*e1, bindings)?;
-            let ne2 = normalize(&*e2, bindings)?;
-            eval_binop(*bop, &ne1, &ne2)
+            let ne1 = normalize(e1, bindings, failures)?;
+            let ne2 = normalize(e2, bindings, failures)?;
+            Ok(eval_binop(*bop, &ne1, &ne2)?)
         }
             
     }
 }
 
+// check a value against the refinements in its declared type
+fn check_refinements(t: &Type, v: &Value, bindings: &HashMap<Ident,Value>, failures: &mut Vec<AssertionFailure>) -> Result<(), Diagnostic> {
+    match (t, v) {
+        (Type::Refined(base, refinement), v) => {
+            check_refinements(base, v, bindings, failures)?;
+            let mut new_bindings = bindings.clone();
+            new_bindings.insert(refinement.binder.clone(), v.clone());
+            match normalize(&refinement.pred, &new_bindings, failures)? {
+                Value::Boolean(true) => (),
+                Value::Boolean(false) => failures.push(AssertionFailure {
+                    message: format!("{} is not a valid value for '{}'", crate::printer::value_source(v), refinement.binder),
+                    span: refinement.pred.span
+                }),
+                result => return Err(format!("Refinement of '{}' is not a boolean. Instead got {result:#?}", refinement.binder).into())
+            }
+        },
+        (Type::Record(fields), Value::Record(hm)) => {
+            for (k, field_type) in fields {
+                if let Some(field) = hm.get(k) {
+                    check_refinements(field_type, field, bindings, failures)?;
+                }
+            }
+        },
+        (Type::List(t), Value::List(l)) => {
+            for v in l {
+                check_refinements(t, v, bindings, failures)?;
+            }
+        },
+        // only the side the value has the shape of is checked
+        (Type::Alternative(a, b), v) => {
+            let shape = crate::typechecker::infer_type(v);
+            if shape <= **a {
+                check_refinements(a, v, bindings, failures)?;
+            } else if shape <= **b {
+                check_refinements(b, v, bindings, failures)?;
+            }
+        },
+        _ => ()
+    }
+    Ok(())
+}
+
+fn overflow(a: i64, op: &str, b: i64) -> String {
+    format!("{a} {op} {b} is too large for an integer, which can be from {} to {}", i64::MIN, i64::MAX)
+}
+
 fn eval_binop(bop: Bop, ne1: &Value, ne2: &Value) -> Result<Value, String>{
     use crate::ast::Value::*;
     match (bop, ne1, ne2) {
@@ -85,9 +180,9 @@ fn eval_binop(bop: Bop, ne1: &Value, ne2: &Value) -> Result<Value, String>{
         (Bop::Pow, Float(a), Float(b)) => Ok(Float(a.powf(*b))),
         (Bop::Pow, Int(a), Float(b)) => Ok(Float((*a as f64).powf(*b))),
         (Bop::Pow, Float(a), Int(b)) => Ok(Float(a.powf(*b as f64))),
-        (Bop::Plus, Int(a), Int(b)) => Ok(Int(a + b)),
-        (Bop::Minus, Int(a), Int(b)) => Ok(Int(a - b)),
-        (Bop::Times, Int(a), Int(b)) => Ok(Int(a * b)),
+        (Bop::Plus, Int(a), Int(b)) => a.checked_add(*b).map(Int).ok_or_else(|| overflow(*a, "+", *b)),
+        (Bop::Minus, Int(a), Int(b)) => a.checked_sub(*b).map(Int).ok_or_else(|| overflow(*a, "-", *b)),
+        (Bop::Times, Int(a), Int(b)) => a.checked_mul(*b).map(Int).ok_or_else(|| overflow(*a, "*", *b)),
         (Bop::Plus, Float(a), Float(b)) => Ok(Float(a + b)),
         (Bop::Minus, Float(a), Float(b)) => Ok(Float(a - b)),
         (Bop::Times, Float(a), Float(b)) => Ok(Float(a * b)),
@@ -110,42 +205,17 @@ fn eval_binop(bop: Bop, ne1: &Value, ne2: &Value) -> Result<Value, String>{
         (Bop::Xor, Int(a), Int(b)) => {
             Ok(Int(a ^ b))
         },
-        (Bop::Eq, Int(a), Int(b)) => Ok(Boolean(a == b)),
-        (Bop::Neq, Int(a), Int(b)) => Ok(Boolean(a != b)),
-        (Bop::Lt, Int(a), Int(b)) => Ok(Boolean(a < b)),
-        (Bop::Gt, Int(a), Int(b)) => Ok(Boolean(a > b)),
-        (Bop::Lte, Int(a), Int(b)) => Ok(Boolean(a <= b)),
-        (Bop::Gte, Int(a), Int(b)) => Ok(Boolean(a >= b)),
-        (Bop::Eq, Float(a), Float(b)) => Ok(Boolean(a == b)),
-        (Bop::Neq, Float(a), Float(b)) => Ok(Boolean(a != b)),
-        (Bop::Lt, Float(a), Float(b)) => Ok(Boolean(a < b)),
-        (Bop::Gt, Float(a), Float(b)) => Ok(Boolean(a > b)),
-        (Bop::Lte, Float(a), Float(b)) => Ok(Boolean(a <= b)),
-        (Bop::Gte, Float(a), Float(b)) => Ok(Boolean(a >= b)),
-        (Bop::Eq, Int(a), Float(b)) => Ok(Boolean(*a as f64 == *b)),
-        (Bop::Neq, Int(a), Float(b)) => Ok(Boolean(*a as f64 != *b)),
-        (Bop::Lt, Int(a), Float(b)) => Ok(Boolean((*a as f64) < *b)),
-        (Bop::Gt, Int(a), Float(b)) => Ok(Boolean(*a as f64 > *b)),
-        (Bop::Lte, Int(a), Float(b)) => Ok(Boolean(*a as f64 <= *b)),
-        (Bop::Gte, Int(a), Float(b)) => Ok(Boolean(*a as f64 >= *b)),
-        (Bop::Eq, Float(a), Int(b)) => Ok(Boolean(*a == *b as f64)),
-        (Bop::Neq, Float(a), Int(b)) => Ok(Boolean(*a != *b as f64)),
-        (Bop::Lt, Float(a), Int(b)) => Ok(Boolean(*a < *b as f64)),
-        (Bop::Gt, Float(a), Int(b)) => Ok(Boolean(*a > *b as f64)),
-        (Bop::Lte, Float(a), Int(b)) => Ok(Boolean(*a <= *b as f64)),
-        (Bop::Gte, Float(a), Int(b)) => Ok(Boolean(*a >= *b as f64)),
         
         // bools
         (Bop::And, Boolean(a), Boolean(b)) => Ok(Boolean(*a && *b)),
         (Bop::Or, Boolean(a), Boolean(b)) => Ok(Boolean(*a || *b)),
         (Bop::Xor, Boolean(a), Boolean(b)) => Ok(Boolean(a ^ b)),
-        (Bop::Eq, Boolean(a), Boolean(b)) => Ok(Boolean(a == b)),
-        (Bop::Neq, Boolean(a), Boolean(b)) => Ok(Boolean(a != b)),
         // null
-        (Bop::Eq, Null, Null) => Ok(Boolean(true)),
-        (Bop::Neq, Null, Null) => Ok(Boolean(false)),
+        (Bop::Coalesce, Null, v) => Ok(v.clone()),
+        (Bop::Coalesce, v, _) => Ok(v.clone()),
+        (Bop::SafeAccess, Null, _) => Ok(Null),
         // text
-        (Bop::Plus, Text(t1), Text(t2)) => Ok(Text(t1.clone() + &t2)),
+        (Bop::Plus, Text(t1), Text(t2)) => Ok(Text(t1.clone() + t2)),
         (Bop::Times, Text(t), Int(n)) => {
             let reps = match usize::try_from(*n){
                 Ok(u) => u,
@@ -163,12 +233,6 @@ fn eval_binop(bop: Bop, ne1: &Value, ne2: &Value) -> Result<Value, String>{
                 Some(c) => Ok(Text(c.to_string()))
             }
         },
-        (Bop::Eq, Text(t1), Text(t2)) => Ok(Boolean(t1 == t2)),
-        (Bop::Neq, Text(t1), Text(t2)) => Ok(Boolean(t1 != t2)),
-        (Bop::Lt, Text(t1), Text(t2)) => Ok(Boolean(t1 < t2)),
-        (Bop::Gt, Text(t1), Text(t2)) => Ok(Boolean(t1 > t2)),
-        (Bop::Lte, Text(t1), Text(t2)) => Ok(Boolean(t1 <= t2)),
-        (Bop::Gte, Text(t1), Text(t2)) => Ok(Boolean(t1 >= t2)),
         //record
         (Bop::Access, Record(hm), Text(key)) => {
             match hm.get(key) {
@@ -176,28 +240,7 @@ fn eval_binop(bop: Bop, ne1: &Value, ne2: &Value) -> Result<Value, String>{
                 Some(v) => Ok(v.clone())
             }
         },
-        (Bop::Eq, Record(hm1), Record(hm2)) => {
-            if hm1.len() != hm2.len() {
-                return Ok(Boolean(false));
-            }
-            for (k, v) in hm1.iter() {
-                match hm2.get(k) {
-                    None => return Ok(Boolean(false)),
-                    Some(v2) => {
-                        if let Boolean(false) = eval_binop(Bop::Eq, v, v2)?{
-                            return Ok(Boolean(false))
-                        }
-                    }
-                }
-            }
-            Ok(Boolean(true))
-        },
-        (Bop::Neq, Record(_), Record(_)) => {
-            match eval_binop(Bop::Eq, ne1, ne2)? {
-                Boolean(b) => Ok(Boolean(!b)),
-                _ => unreachable!()
-            }
-        },
+        (Bop::SafeAccess, Record(hm), Text(key)) => Ok(hm.get(key).cloned().unwrap_or(Null)),
         (Bop::Plus, Record(hm1), Record(hm2)) => {
             //When joining records, prefer the variable in the second one if there's overlap
             let mut joined_hashmap = hm1.clone();
@@ -220,31 +263,106 @@ fn eval_binop(bop: Bop, ne1: &Value, ne2: &Value) -> Result<Value, String>{
             joined.extend(v2.clone());
             Ok(List(joined))
         },
-        (Bop::Eq, List(v1), List(v2)) => {
-            if v1.len() != v2.len() {
-                return Ok(Boolean(false));
-            }
-            for (e1, e2) in v1.iter().zip(v2) {
-               if let Boolean(false) = eval_binop(Bop::Eq, e1, e2)? {
-                return Ok(Boolean(false));
-               }
-            }
-            Ok(Boolean(true))
-        },
-        (Bop::Neq, List(_), List(_)) => {
-            match eval_binop(Bop::Eq, ne1, ne2)? {
+
+        //membership
+        (Bop::In, v, List(l)) => Ok(Boolean(l.contains(v))),
+        (Bop::In, Text(key), Record(hm)) => Ok(Boolean(hm.contains_key(key))),
+        (Bop::In, Text(sub), Text(t)) => Ok(Boolean(t.contains(sub.as_str()))),
+        (Bop::NotIn, _, List(_))
+        | (Bop::NotIn, Text(_), Record(_))
+        | (Bop::NotIn, Text(_), Text(_)) => {
+            match eval_binop(Bop::In, ne1, ne2)? {
                 Boolean(b) => Ok(Boolean(!b)),
                 _ => unreachable!()
             }
         },
 
-        // lambda has no binops
-        // TODO: can we do structural equality for lambdas?
-        // comparisons between any other types are not equal
-        (Bop::Eq, _, _) => Ok(Boolean(false)),
-        (Bop::Neq, _, _) => Ok(Boolean(true)),
-        _ => Err(format!("This should have been caught by the typechecker"))
+        // comparisons use the structural equality and ordering of values
+        // lambdas can't be compared, and values of different types are an error rather than unequal
+        (Bop::Eq, a, b) if a.comparable(b) => Ok(Boolean(a == b)),
+        (Bop::Neq, a, b) if a.comparable(b) => Ok(Boolean(a != b)),
+        (Bop::Eq, a, b) | (Bop::Neq, a, b) => Err(format!("Cannot compare {} and {}", value_source(a), value_source(b))),
+        // only numbers, text and versions have an ordering in the language
+        (Bop::Lt, a, b)
+        | (Bop::Gt, a, b)
+        | (Bop::Lte, a, b)
+        | (Bop::Gte, a, b) if !matches!((a, b), (Int(_) | Float(_), Int(_) | Float(_)) | (Text(_), Text(_)) | (Version(_), Version(_))) => {
+            Err(format!("Cannot order {} and {}", value_source(a), value_source(b)))
+        },
+        (Bop::Lt, a, b) => Ok(Boolean(a < b)),
+        (Bop::Gt, a, b) => Ok(Boolean(a > b)),
+        (Bop::Lte, a, b) => Ok(Boolean(a <= b)),
+        (Bop::Gte, a, b) => Ok(Boolean(a >= b)),
+        _ => Err("This should have been caught by the typechecker".to_string())
     }
 }
 
-// takes in a normalized ast
\ No newline at end of file
+// takes in a normalized ast
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(source: &str) -> Result<Value, String> {
+        let e = crate::parser::parse(source).unwrap();
+        normalize(&e, &crate::builtins::values(Default::default()), &mut Vec::new()).map_err(|d| d.message)
+    }
+
+    fn failures(source: &str) -> Vec<String> {
+        let e = crate::parser::parse(source).unwrap();
+        let mut failures = Vec::new();
+        normalize(&e, &crate::builtins::values(Default::default()), &mut failures).unwrap();
+        failures.into_iter().map(|f| f.message).collect()
+    }
+
+    #[test]
+    fn integer_overflow_is_an_error() {
+        assert!(eval("9223372036854775807 + 1").unwrap_err().contains("too large"));
+        assert!(eval("0 - 9223372036854775807 - 2").unwrap_err().contains("too large"));
+        assert!(eval("4611686018427387904 * 2").unwrap_err().contains("too large"));
+        assert!(eval("-(0 - 9223372036854775807 - 1)").unwrap_err().contains("too large"));
+        assert_eq!(eval("9223372036854775806 + 1"), Ok(Value::Int(i64::MAX)));
+    }
+
+    #[test]
+    fn unary_operators_on_the_wrong_type_are_errors() {
+        assert_eq!(eval("(\\x -> -x) \"a\""), Err("Cannot negate \"a\"".to_string()));
+        assert_eq!(eval("(\\x -> !x) 1"), Err("Cannot apply ! to 1".to_string()));
+    }
+
+    #[test]
+    fn assertion_conditions_can_use_operators() {
+        assert_eq!(failures("let port = 70000 in assert port < 65536 : \"port out of range\" in port"), ["port out of range"]);
+        assert!(failures("let port = 80 in assert port < 65536 && port > 0 : \"port out of range\" in port").is_empty());
+    }
+
+    #[test]
+    fn refinement_failures_show_the_value_as_source() {
+        let source = "let x : { port : Natural where port < 65536 } = { port = 70000 } in x";
+        assert_eq!(failures(source), ["70000 is not a valid value for 'port'"]);
+        let source = "let x : { name : Text where name != \"\" } = { name = \"\" } in x";
+        assert_eq!(failures(source), ["\"\" is not a valid value for 'name'"]);
+    }
+
+    #[test]
+    fn only_the_matching_side_of_an_alternative_is_checked() {
+        // a > 1 would be an error on text
+        let source = "let x : { a : Natural where a > 1 } | { a : Text where a != \"\" } = { a = \"b\" } in x";
+        assert!(failures(source).is_empty());
+        let source = "let x : { a : Natural where a > 1 }? = null in x";
+        assert!(failures(source).is_empty());
+        let source = "let x : { a : Natural where a > 1 }? = { a = 0 } in x";
+        assert_eq!(failures(source), ["0 is not a valid value for 'a'"]);
+    }
+
+    #[test]
+    fn data_is_checked_against_its_declared_type() {
+        let typecheck = |source: &str| crate::typechecker::typecheck(&crate::parser::parse(source).unwrap(), &crate::builtins::types()).map(|_| ());
+        let source = r#"let x : { a : Text, b : [Natural] } = (fromJSON (shellEscape "")) in x"#;
+        assert!(typecheck(source).is_ok());
+        assert_eq!(eval(r#"let x : { a : Text } = ((\s -> fromJSON s) "{\"a\": 1}") in x"#), Err("x.a is 1, which isn't Text".to_string()));
+        assert_eq!(eval(r#"let x : { a : Text } = ((\s -> fromJSON s) "{}") in x"#), Err("x has no field a, which has to be Text".to_string()));
+        assert_eq!(eval(r#"let x : [Natural] = ((\s -> fromJSON s) "[1, -2]") in x"#), Err("x[1] is -2, which isn't Natural".to_string()));
+        assert!(eval(r#"let x : { a : Text? } = ((\s -> fromJSON s) "{\"a\": null, \"b\": 1}") in x"#).is_ok());
+        assert!(eval(r#"let f : Text -> Text = (\x -> x) in f "a""#).is_ok());
+    }
+}
@@ -0,0 +1,420 @@
+use crate::block::Block;
+use serde_json::{json, Value as Json};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+// The record of what each apply changed, so that it can be undone. Every apply that changes anything
+// makes a new generation, a numbered directory in the generations directory:
+//   journal.json    when it was applied, from which commit of the config, and every path it changed,
+//                   with what was there before and after, and the managed block it changed, if any
+//   files/3.before  the contents of a file that was there before, for the change at index 3
+//   files/3.after   the contents it was left with
+// a change is recorded before it's made, so that a generation can still be undone if apply fails partway
+
+// what is at a path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum State {
+    Missing,
+    File { contents: Vec<u8>, mode: u32 },
+    Link(PathBuf),
+    Dir
+}
+
+impl State {
+    // what is at the path now, without following links
+    pub fn read(path: &Path) -> Result<State, String> {
+        let metadata = match fs::symlink_metadata(path) {
+            Ok(m) => m,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(State::Missing),
+            Err(e) => return Err(format!("Cannot read {}: {e}", path.display()))
+        };
+        if metadata.file_type().is_symlink() {
+            fs::read_link(path).map(State::Link).map_err(|e| format!("Cannot read the link {}: {e}", path.display()))
+        } else if metadata.is_dir() {
+            Ok(State::Dir)
+        } else {
+            let contents = fs::read(path).map_err(|e| format!("Cannot read {}: {e}", path.display()))?;
+            Ok(State::File { contents, mode: metadata.permissions().mode() & 0o7777 })
+        }
+    }
+
+    // makes the path have this state, replacing whatever is there now
+    pub fn write(&self, path: &Path) -> Result<(), String> {
+        let current = State::read(path)?;
+        if current == *self {
+            return Ok(());
+        }
+        let error = |e: io::Error| format!("Cannot write {}: {e}", path.display());
+        match (&current, self) {
+            // renaming the new file over the old one replaces it all at once
+            (State::Missing, _) | (State::File { .. } | State::Link(_), State::File { .. }) => (),
+            // only empty directories are removed, so that nothing is lost that apply didn't make
+            (State::Dir, _) => fs::remove_dir(path).map_err(error)?,
+            (State::File { .. } | State::Link(_), _) => fs::remove_file(path).map_err(error)?
+        }
+        match self {
+            State::Missing => Ok(()),
+            State::File { contents, mode } => write_atomic(path, contents, *mode).map_err(error),
+            State::Link(target) => std::os::unix::fs::symlink(target, path).map_err(error),
+            State::Dir => fs::create_dir(path).map_err(error)
+        }
+    }
+
+    fn to_json(&self, blob: &str) -> Json {
+        match self {
+            State::Missing => json!({ "type": "missing" }),
+            State::File { mode, .. } => json!({ "type": "file", "mode": mode, "contents": blob }),
+            State::Link(target) => json!({ "type": "link", "target": target.to_string_lossy() }),
+            State::Dir => json!({ "type": "dir" })
+        }
+    }
+
+    fn from_json(json: &Json, dir: &Path) -> Result<State, String> {
+        Ok(match json["type"].as_str() {
+            Some("missing") => State::Missing,
+            Some("file") => {
+                let blob = json["contents"].as_str().ok_or("a file has no contents")?;
+                let contents = fs::read(dir.join(blob)).map_err(|e| format!("Cannot read {blob}: {e}"))?;
+                let mode = json["mode"].as_u64().ok_or("a file has no mode")? as u32;
+                State::File { contents, mode }
+            },
+            Some("link") => State::Link(PathBuf::from(json["target"].as_str().ok_or("a link has no target")?)),
+            Some("dir") => State::Dir,
+            _ => return Err(format!("{json} is not a state"))
+        })
+    }
+}
+
+// writes a file next to the path and renames it over the path, so that the file is never seen half written
+pub fn write_atomic(path: &Path, contents: &[u8], mode: u32) -> io::Result<()> {
+    let name = path.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "the path has no file name"))?;
+    let temporary = path.with_file_name(format!(".{}.{}.tmp", name.to_string_lossy(), std::process::id()));
+    let write = || {
+        let mut file = fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(&temporary)?;
+        file.write_all(contents)?;
+        // the mode is set explicitly, as the umask would change it
+        file.set_permissions(fs::Permissions::from_mode(mode))?;
+        file.sync_all()?;
+        fs::rename(&temporary, path)?;
+        // the rename is only durable once the directory it's in is synced
+        let parent = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        fs::File::open(parent)?.sync_all()
+    };
+    let result = write();
+    if result.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+    result
+}
+
+// a path that apply changed
+#[derive(Debug, Clone)]
+pub struct Change {
+    pub path: PathBuf,
+    pub before: State,
+    pub after: State,
+    // the managed block the change was to, if it was only to a block in the file
+    pub block: Option<Block>
+}
+
+#[derive(Debug, Clone)]
+pub struct Generation {
+    pub number: u64,
+    // seconds since the epoch
+    pub time: u64,
+    // the git commit the config directory was at, if it's a repository
+    pub commit: Option<String>,
+    // false if apply stopped partway
+    pub complete: bool,
+    // the newest generation when this one was reverted, if it was. A revert happens after that generation and before the next
+    pub reverted: Option<u64>,
+    pub changes: Vec<Change>,
+    // the commands that were run, which can't be undone
+    pub commands: Vec<String>,
+    dir: PathBuf
+}
+
+const JOURNAL_FILE: &str = "journal.json";
+
+// where the generations are kept
+pub fn generations_dir() -> Result<PathBuf, String> {
+    crate::config::state_dir().map(|dir| dir.join("generations"))
+}
+
+// every generation, oldest first
+pub fn list(root: &Path) -> Result<Vec<Generation>, String> {
+    let entries = match fs::read_dir(root) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("Cannot read {}: {e}", root.display()))
+    };
+    let mut generations = Vec::new();
+    for entry in entries {
+        let entry = entry.map_err(|e| format!("Cannot read {}: {e}", root.display()))?;
+        // anything else in the directory isn't a generation
+        if entry.file_name().to_str().is_some_and(|name| name.parse::<u64>().is_ok()) {
+            generations.push(Generation::load(&entry.path())?);
+        }
+    }
+    generations.sort_by_key(|g| g.number);
+    Ok(generations)
+}
+
+impl Generation {
+    // a new generation, numbered after the newest one
+    pub fn create(root: &Path, commit: Option<String>) -> Result<Generation, String> {
+        let number = list(root)?.last().map_or(1, |g| g.number + 1);
+        let dir = root.join(number.to_string());
+        fs::create_dir_all(dir.join("files")).map_err(|e| format!("Cannot create {}: {e}", dir.display()))?;
+        let time = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        let generation = Generation { number, time, commit, complete: false, reverted: None, changes: Vec::new(), commands: Vec::new(), dir };
+        generation.save()?;
+        Ok(generation)
+    }
+
+    pub fn load(dir: &Path) -> Result<Generation, String> {
+        let path = dir.join(JOURNAL_FILE);
+        let error = |message: &str| format!("{}: {message}", path.display());
+        let source = fs::read_to_string(&path).map_err(|e| error(&format!("Cannot read it: {e}")))?;
+        let json: Json = serde_json::from_str(&source).map_err(|e| error(&format!("Invalid JSON: {e}")))?;
+        let mut changes = Vec::new();
+        for change in json["changes"].as_array().ok_or_else(|| error("changes must be a list"))? {
+            let path = change["path"].as_str().ok_or_else(|| error("a change has no path"))?;
+            let block = match (change["block"]["name"].as_str(), change["block"]["comment"].as_str()) {
+                (Some(name), Some(comment)) => Some(Block { name: name.to_string(), comment: comment.to_string() }),
+                _ => None
+            };
+            changes.push(Change {
+                path: PathBuf::from(path),
+                before: State::from_json(&change["before"], dir).map_err(|e| error(&e))?,
+                after: State::from_json(&change["after"], dir).map_err(|e| error(&e))?,
+                block
+            });
+        }
+        let commands = json["commands"].as_array().ok_or_else(|| error("commands must be a list"))?
+            .iter().map(|c| c.as_str().map(str::to_string).ok_or_else(|| error("commands must be text")))
+            .collect::<Result<Vec<String>, String>>()?;
+        Ok(Generation {
+            number: json["generation"].as_u64().ok_or_else(|| error("generation must be a number"))?,
+            time: json["time"].as_u64().ok_or_else(|| error("time must be a number"))?,
+            commit: json["commit"].as_str().map(str::to_string),
+            complete: json["complete"].as_bool().ok_or_else(|| error("complete must be true or false"))?,
+            reverted: match &json["reverted"] {
+                Json::Null => None,
+                n => Some(n.as_u64().ok_or_else(|| error("reverted must be a generation number or null"))?)
+            },
+            changes,
+            commands,
+            dir: dir.to_path_buf()
+        })
+    }
+
+    // the journal is replaced all at once, so it's never left half written
+    pub fn save(&self) -> Result<(), String> {
+        let changes: Vec<Json> = self.changes.iter().enumerate().map(|(i, change)| json!({
+            "path": change.path.to_string_lossy(),
+            "before": change.before.to_json(&format!("files/{i}.before")),
+            "after": change.after.to_json(&format!("files/{i}.after")),
+            "block": change.block.as_ref().map(|b| json!({ "name": b.name, "comment": b.comment }))
+        })).collect();
+        let json = json!({
+            "generation": self.number,
+            "time": self.time,
+            "commit": self.commit,
+            "complete": self.complete,
+            "reverted": self.reverted,
+            "changes": changes,
+            "commands": self.commands
+        });
+        let source = serde_json::to_string_pretty(&json).map_err(|e| e.to_string())? + "\n";
+        let path = self.dir.join(JOURNAL_FILE);
+        write_atomic(&path, source.as_bytes(), 0o644).map_err(|e| format!("Cannot write {}: {e}", path.display()))
+    }
+
+    // records a change, before it's made
+    pub fn record(&mut self, change: Change) -> Result<(), String> {
+        self.changes.push(change);
+        self.write_contents(self.changes.len() - 1)?;
+        self.save()
+    }
+
+    // records what a change left at its path, once it's made
+    pub fn made(&mut self, after: State) -> Result<(), String> {
+        let i = self.changes.len() - 1;
+        self.changes[i].after = after;
+        self.write_contents(i)?;
+        self.save()
+    }
+
+    // how many paths were created, changed and removed, and how many commands were run
+    pub fn summary(&self) -> String {
+        let (mut created, mut changed, mut removed) = (0, 0, 0);
+        for change in self.changes.iter().filter(|c| c.before != c.after) {
+            match (&change.before, &change.after) {
+                (State::Missing, _) => created += 1,
+                (_, State::Missing) => removed += 1,
+                _ => changed += 1
+            }
+        }
+        let mut parts: Vec<String> = [(created, "created"), (changed, "changed"), (removed, "removed")].iter()
+            .filter(|(n, _)| *n > 0)
+            .map(|(n, what)| format!("{n} {what}"))
+            .collect();
+        match self.commands.len() {
+            0 => (),
+            1 => parts.push("1 command".to_string()),
+            n => parts.push(format!("{n} commands"))
+        }
+        if parts.is_empty() { "no changes".to_string() } else { parts.join(", ") }
+    }
+
+    pub fn remove(self) -> Result<(), String> {
+        fs::remove_dir_all(&self.dir).map_err(|e| format!("Cannot remove {}: {e}", self.dir.display()))
+    }
+
+    fn write_contents(&self, i: usize) -> Result<(), String> {
+        let change = &self.changes[i];
+        for (state, suffix) in [(&change.before, "before"), (&change.after, "after")] {
+            if let State::File { contents, .. } = state {
+                let path = self.dir.join("files").join(format!("{i}.{suffix}"));
+                fs::write(&path, contents).map_err(|e| format!("Cannot write {}: {e}", path.display()))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// what every path that was ever applied looked like right after generation n was applied. Paths that weren't applied yet
+// have what was there before the first generation that changed them. Generations that were reverted before n was applied don't count
+pub fn snapshot(generations: &[Generation], n: u64) -> HashMap<PathBuf, State> {
+    let mut states = HashMap::new();
+    for g in generations {
+        for change in &g.changes {
+            states.entry(change.path.clone()).or_insert_with(|| change.before.clone());
+        }
+    }
+    for g in generations.iter().filter(|g| g.number <= n && g.reverted.is_none_or(|after| after >= n)) {
+        for change in &g.changes {
+            states.insert(change.path.clone(), change.after.clone());
+        }
+    }
+    states
+}
+
+// deletes all but the newest `keep` generations, returning the numbers of the ones that were deleted.
+// they can't be reverted any more. The generation that's applied now is always kept, as it's what a revert undoes first
+pub fn gc(root: &Path, keep: usize) -> Result<Vec<u64>, String> {
+    let mut generations = list(root)?;
+    let current = generations.iter().rev().find(|g| g.reverted.is_none()).map(|g| g.number);
+    let old = generations.len().saturating_sub(keep);
+    let mut deleted = Vec::new();
+    for g in generations.drain(..old).filter(|g| Some(g.number) != current) {
+        deleted.push(g.number);
+        g.remove()?;
+    }
+    Ok(deleted)
+}
+
+// a time as UTC, like 2024-03-01 14:05:09
+pub fn format_time(time: u64) -> String {
+    let (days, seconds) = (time / 86400, time % 86400);
+    // from the number of days since 1970-01-01 to the date, counting in 400 year eras that start on March 1st
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch;
+
+    fn file(contents: &str) -> State {
+        State::File { contents: contents.as_bytes().to_vec(), mode: 0o644 }
+    }
+
+    fn generation(number: u64, reverted: Option<u64>, changes: Vec<(&str, State, State)>) -> Generation {
+        let changes = changes.into_iter().map(|(path, before, after)| Change { path: PathBuf::from(path), before, after, block: None }).collect();
+        Generation { number, time: 0, commit: None, complete: true, reverted, changes, commands: Vec::new(), dir: PathBuf::new() }
+    }
+
+    #[test]
+    fn snapshots_count_generations_reverted_later() {
+        // 1 and 2 were applied, then both reverted
+        let generations = [
+            generation(1, Some(2), vec![("a", State::Missing, file("1"))]),
+            generation(2, Some(2), vec![])
+        ];
+        assert_eq!(snapshot(&generations, 1)[Path::new("a")], file("1"));
+        assert_eq!(snapshot(&generations, 2)[Path::new("a")], file("1"));
+    }
+
+    #[test]
+    fn snapshots_skip_generations_reverted_earlier() {
+        // 1 was applied, 2 was applied and reverted, then 3 was applied
+        let generations = [
+            generation(1, None, vec![("a", State::Missing, file("1"))]),
+            generation(2, Some(2), vec![("a", file("1"), file("2"))]),
+            generation(3, None, vec![("b", State::Missing, file("3"))])
+        ];
+        assert_eq!(snapshot(&generations, 2)[Path::new("a")], file("2"));
+        assert_eq!(snapshot(&generations, 3)[Path::new("a")], file("1"));
+        assert_eq!(snapshot(&generations, 1)[Path::new("b")], State::Missing);
+    }
+
+    fn numbers(root: &Path) -> Vec<u64> {
+        list(root).unwrap().iter().map(|g| g.number).collect()
+    }
+
+    #[test]
+    fn gc_deletes_the_oldest_generations_but_not_the_applied_one() {
+        let root = scratch::dir("journal", "gc");
+        for _ in 0..3 {
+            Generation::create(&root, None).unwrap();
+        }
+        let mut newest = list(&root).unwrap().pop().unwrap();
+        newest.reverted = Some(3);
+        newest.save().unwrap();
+        assert_eq!(gc(&root, 1).unwrap(), [1]);
+        assert_eq!(numbers(&root), [2, 3]);
+        assert_eq!(gc(&root, 0).unwrap(), [3]);
+        assert_eq!(numbers(&root), [2]);
+        // new generations carry on from the newest that's left
+        assert_eq!(Generation::create(&root, None).unwrap().number, 3);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn atomic_writes_replace_files_and_links() {
+        let dir = scratch::dir("journal", "atomic");
+        let path = dir.join("file");
+        write_atomic(&path, b"one", 0o600).unwrap();
+        assert_eq!(State::read(&path).unwrap(), State::File { contents: b"one".to_vec(), mode: 0o600 });
+        write_atomic(&path, b"two", 0o755).unwrap();
+        assert_eq!(State::read(&path).unwrap(), State::File { contents: b"two".to_vec(), mode: 0o755 });
+        // the link is replaced, rather than the file it points to being written
+        let link = dir.join("link");
+        std::os::unix::fs::symlink(&path, &link).unwrap();
+        write_atomic(&link, b"three", 0o644).unwrap();
+        assert_eq!(State::read(&link).unwrap(), file("three"));
+        assert_eq!(fs::read(&path).unwrap(), b"two");
+        // nothing is left behind when the rename fails
+        fs::create_dir(dir.join("dir")).unwrap();
+        fs::write(dir.join("dir/inside"), "").unwrap();
+        assert!(write_atomic(&dir.join("dir"), b"four", 0o644).is_err());
+        let mut names: Vec<String> = fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().to_string()).collect();
+        names.sort();
+        assert_eq!(names, ["dir", "file", "link"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,28 +1,340 @@
 extern crate pest;
 #[macro_use]
 extern crate pest_derive;
-// use clap::Parser;
+use clap::Parser;
 use std::fs;
-use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
 
 mod cli;
 mod parser;
 mod ast;
-// mod typechecker;
+mod typechecker;
 mod builtins;
 mod interpreter;
+mod output;
+mod input;
+mod cst;
+mod printer;
+mod convert;
+mod recovery;
+mod edit;
+mod config;
+mod init;
+mod plan;
+mod apply;
+mod journal;
+mod block;
+#[cfg(test)]
+mod scratch;
 
 fn main() {
-    // let cli = cli::Cli::parse();
-    // println!("Hello, world!");
-    test();
+    let cli = cli::Cli::parse();
+    let config_dir = cli.config_dir;
+    // the config's entry file, for commands that weren't given a file, and where the file's imports are looked for
+    let entry = |file: Option<PathBuf>| match file {
+        Some(file) => Ok((builtins::Imports::new(&file, Vec::new()), file)),
+        None => config::find(config_dir.clone()).map(|config| (builtins::Imports::new(&config.entry(), config.library_paths()), config.entry()))
+    };
+    let result = match cli.command {
+        cli::Commands::Eval { file, output, compact, sort_keys } => {
+            let order = if sort_keys { ast::KeyOrder::Sorted } else { ast::KeyOrder::Insertion };
+            eval(file, config_dir.clone(), output, !compact, order)
+        },
+        cli::Commands::Init { git, detect, force } => init(config_dir.clone(), git, detect, force),
+        cli::Commands::Validate { file, format } => entry(file).and_then(|(imports, file)| validate(&file, imports, format)),
+        cli::Commands::Plan { file } => entry(file).and_then(|(imports, file)| print_plan(&file, imports)),
+        cli::Commands::Apply { file, dry_run } => entry(file).and_then(|(imports, file)| apply(&file, imports, dry_run)),
+        cli::Commands::Revert { to, dry_run, force } => revert(to, dry_run, force),
+        cli::Commands::Generations {} => generations(),
+        cli::Commands::Diff { from, to } => diff(from, to),
+        cli::Commands::Gc { keep } => gc(keep),
+        cli::Commands::Uninstall { force } => uninstall(force),
+        cli::Commands::Convert { file, name } => convert(&file, name),
+        cli::Commands::Format { file, check, width } => entry(file).and_then(|(_, file)| format(&file, check, width)),
+        cli::Commands::Set { path, value, file } => entry(file).and_then(|(_, file)| set(&file, &path, &value)),
+        // TODO: the other commands
+        _ => Err("This command isn't implemented yet".to_string())
+    };
+    if let Err(e) = result {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
 }
 
-fn test() -> Result<(), String>{
-    let unparsed_file = fs::read_to_string("testfile").expect("cannot read file");
-    let e = parser::parse(&unparsed_file).expect("unsuccessful parse");
-    // let te: ast::TypedExpr = typechecker::typecheck(&e, &HashMap::new())?;
-    let reduced: ast::Value = interpreter::normalize(&e, &HashMap::new())?;
-    println!("{:#?}", reduced);
+// the name to report errors with and the contents of a source file, where - is stdin
+fn read_source(file: &Path) -> Result<(String, String), String> {
+    if file == Path::new("-") {
+        let mut source = String::new();
+        io::stdin().read_to_string(&mut source).map_err(|e| format!("Cannot read stdin: {e}"))?;
+        return Ok(("<stdin>".to_string(), source));
+    }
+    let source = fs::read_to_string(file).map_err(|e| format!("Cannot read {}: {e}", file.display()))?;
+    Ok((file.display().to_string(), source))
+}
+
+// a closed pipe, like when the output goes to head, isn't an error
+fn write_stdout(out: &str) -> Result<(), String> {
+    match io::stdout().write_all(out.as_bytes()) {
+        Err(e) if e.kind() != io::ErrorKind::BrokenPipe => Err(format!("Cannot write the output: {e}")),
+        _ => Ok(())
+    }
+}
+
+// without a file, this runs the config, in the config's first output format if none is given
+fn eval(file: Option<PathBuf>, config_dir: Option<PathBuf>, format: Option<cli::OutputFormat>, pretty: bool, order: ast::KeyOrder) -> Result<(), String>{
+    let (file, imports, format) = match file {
+        Some(file) => (file.clone(), builtins::Imports::new(&file, Vec::new()), format),
+        None => {
+            let config = config::find(config_dir)?;
+            (config.entry(), builtins::Imports::new(&config.entry(), config.library_paths()), format.or(config.manifest.outputs.first().copied()))
+        }
+    };
+    let format = format.unwrap_or(cli::OutputFormat::Json);
+    let reduced = evaluate(&file, imports)?;
+    write_stdout(&output::render(&reduced, format, pretty, order).map_err(|e| e.to_string())?)
+}
+
+fn print_plan(file: &Path, imports: builtins::Imports) -> Result<(), String> {
+    let plan = plan::plan(&evaluate(file, imports)?)?;
+    write_stdout(&plan.to_string())
+}
+
+fn apply(file: &Path, imports: builtins::Imports, dry_run: bool) -> Result<(), String> {
+    let plan = plan::plan(&evaluate(file, imports)?)?;
+    let root = journal::generations_dir()?;
+    if dry_run {
+        return write_stdout(&apply::dry_run(&plan, &root)?);
+    }
+    let generation = apply::apply(&plan, &root, commit(file), |i, count, op, done| {
+        println!("[{i}/{count}] {op}{}", if done { " (already done)" } else { "" });
+    })?;
+    if let Some(n) = generation {
+        println!("Applied generation {n}");
+    }
+    Ok(())
+}
+
+// the commit the repository the file is in is at
+fn commit(file: &Path) -> Option<String> {
+    let dir = file.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let output = std::process::Command::new("git").arg("-C").arg(dir).args(["rev-parse", "HEAD"])
+        .stderr(std::process::Stdio::null()).output().ok()?;
+    let commit = String::from_utf8(output.stdout).ok()?;
+    Some(commit.trim().to_string()).filter(|c| output.status.success() && !c.is_empty())
+}
+
+fn revert(to: Option<u64>, dry_run: bool, force: bool) -> Result<(), String> {
+    let root = journal::generations_dir()?;
+    if dry_run {
+        return write_stdout(&apply::revert_dry_run(&root, to, force)?);
+    }
+    let reverted = apply::revert(&root, to, force)?;
+    if reverted.is_empty() {
+        println!("Nothing to revert");
+    }
+    for g in reverted {
+        eprint!("{}", apply::commands_note(&g));
+        println!("Reverted generation {}", g.number);
+    }
+    Ok(())
+}
+
+// the config directory is left alone, as it's the user's
+fn uninstall(force: bool) -> Result<(), String> {
+    revert(Some(0), false, force)?;
+    let state = config::state_dir()?;
+    match fs::remove_dir_all(&state) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(format!("Cannot remove {}: {e}", state.display())),
+        _ => Ok(())
+    }
+}
+
+fn generations() -> Result<(), String> {
+    let mut out = String::new();
+    for g in journal::list(&journal::generations_dir()?)? {
+        let commit = g.commit.as_deref().map_or("-------", |c| &c[..c.len().min(7)]);
+        let status = match (g.reverted, g.complete) {
+            (Some(_), _) => " (reverted)",
+            (None, false) => " (incomplete)",
+            (None, true) => ""
+        };
+        out += &format!("{:>4}  {} UTC  {commit}  {}{status}\n", g.number, journal::format_time(g.time), g.summary());
+    }
+    write_stdout(&out)
+}
+
+fn diff(from: u64, to: u64) -> Result<(), String> {
+    let generations = journal::list(&journal::generations_dir()?)?;
+    for n in [from, to] {
+        if !generations.iter().any(|g| g.number == n) {
+            return Err(format!("There is no generation {n}"));
+        }
+    }
+    // both have every path that was ever applied
+    let (before, after) = (journal::snapshot(&generations, from), journal::snapshot(&generations, to));
+    let mut paths: Vec<&PathBuf> = before.keys().collect();
+    paths.sort();
+    let out: String = paths.into_iter().map(|path| apply::describe(path, &before[path], &after[path])).collect();
+    write_stdout(&out)
+}
+
+fn gc(keep: usize) -> Result<(), String> {
+    for n in journal::gc(&journal::generations_dir()?, keep)? {
+        println!("Deleted generation {n}");
+    }
+    Ok(())
+}
+
+// runs a file, failing if it doesn't typecheck or an assertion doesn't hold
+fn evaluate(file: &Path, imports: builtins::Imports) -> Result<ast::Value, String> {
+    let (name, unparsed_file) = read_source(file)?;
+    let e = parser::parse(&unparsed_file).map_err(|d| ast::report(&name, &unparsed_file, &d))?;
+    let e = typechecker::resolve_aliases(&e, &std::collections::HashMap::new()).map_err(|e| format!("{name}: {e}"))?;
+    let _te: ast::TypedExpr = typechecker::typecheck(&e, &builtins::types()).map_err(|d| ast::report(&name, &unparsed_file, &[d]))?;
+    let mut failures = Vec::new();
+    let reduced: ast::Value = interpreter::normalize(&e, &builtins::values(imports), &mut failures).map_err(|d| ast::report(&name, &unparsed_file, &[d]))?;
+    if !failures.is_empty() {
+        let failures: Vec<ast::Diagnostic> = failures.into_iter().map(interpreter::AssertionFailure::into_diagnostic).collect();
+        return Err(ast::report(&name, &unparsed_file, &failures));
+    }
+    Ok(reduced)
+}
+
+fn init(dir: Option<PathBuf>, git: bool, detect: bool, force: bool) -> Result<(), String> {
+    let dir = config::config_dir(dir)?;
+    let entry = dir.join(config::ENTRY_FILE);
+    let manifest = dir.join(config::MANIFEST_FILE);
+    for file in [&entry, &manifest] {
+        if file.exists() && !force {
+            return Err(format!("{} already exists. Use --force to replace it", file.display()));
+        }
+    }
+    let source = init::starter(detect)?;
+    // the starter has to work as it is
+    let e = parser::parse(&source).map_err(|d| ast::report("<starter>", &source, &d))?;
+    typechecker::typecheck(&e, &builtins::types()).map_err(|d| ast::report("<starter>", &source, &[d]))?;
+    fs::create_dir_all(&dir).map_err(|e| format!("Cannot create {}: {e}", dir.display()))?;
+    fs::write(&entry, source).map_err(|e| format!("Cannot write {}: {e}", entry.display()))?;
+    fs::write(&manifest, config::Manifest::starter()).map_err(|e| format!("Cannot write {}: {e}", manifest.display()))?;
+    if git && !dir.join(".git").exists() {
+        let status = std::process::Command::new("git").arg("init").arg("--quiet").arg(&dir).status()
+            .map_err(|e| format!("Cannot run git: {e}"))?;
+        if !status.success() {
+            return Err(format!("git init failed in {}", dir.display()));
+        }
+    }
+    println!("Created {}", entry.display());
+    Ok(())
+}
+
+// exit codes of validate, so that scripts can tell what kind of problem a file has
+const SYNTAX_ERROR: i32 = 3;
+const TYPE_ERROR: i32 = 4;
+const EVALUATION_ERROR: i32 = 5;
+
+fn validate(file: &Path, imports: builtins::Imports, format: cli::DiagnosticFormat) -> Result<(), String> {
+    let (name, source) = read_source(file)?;
+    let (problems, exit_code) = problems(&source, imports);
+    match format {
+        cli::DiagnosticFormat::Text => {
+            let diagnostics: Vec<ast::Diagnostic> = problems.into_iter().map(|(_, d)| d).collect();
+            if !diagnostics.is_empty() {
+                eprintln!("{}", ast::report(&name, &source, &diagnostics));
+            }
+        },
+        cli::DiagnosticFormat::Json => {
+            let json: Vec<serde_json::Value> = problems.iter().map(|(code, d)| {
+                let span = d.span.map(|span| {
+                    let (line, column) = span.line_col(&source);
+                    let (end_line, end_column) = ast::Span { start: span.end, end: span.end }.line_col(&source);
+                    serde_json::json!({
+                        "start": span.start, "end": span.end,
+                        "line": line, "column": column, "end_line": end_line, "end_column": end_column
+                    })
+                });
+                serde_json::json!({
+                    "file": name, "span": span, "severity": "error", "code": code, "message": d.message, "hint": d.hint
+                })
+            }).collect();
+            write_stdout(&(serde_json::to_string_pretty(&json).map_err(|e| e.to_string())? + "\n"))?;
+        }
+    }
+    if exit_code != 0 {
+        std::process::exit(exit_code);
+    }
+    Ok(())
+}
+
+// everything wrong with a file, each with a code saying what kind of problem it is, and the exit code for the worst of them.
+// each stage needs the one before it to succeed
+fn problems(source: &str, imports: builtins::Imports) -> (Vec<(&'static str, ast::Diagnostic)>, i32) {
+    let e = match parser::parse(source) {
+        Ok(e) => e,
+        Err(diagnostics) => return (diagnostics.into_iter().map(|d| ("syntax-error", d)).collect(), SYNTAX_ERROR)
+    };
+    let e = match typechecker::resolve_aliases(&e, &std::collections::HashMap::new()) {
+        Ok(e) => e,
+        Err(message) => return (vec![("type-error", ast::Diagnostic::from(message))], TYPE_ERROR)
+    };
+    let type_errors = typechecker::errors(&e, &builtins::types());
+    if !type_errors.is_empty() {
+        return (type_errors.into_iter().map(|d| ("type-error", d)).collect(), TYPE_ERROR);
+    }
+    let mut failures = Vec::new();
+    let result = interpreter::normalize(&e, &builtins::values(imports), &mut failures);
+    let mut problems: Vec<(&'static str, ast::Diagnostic)> = failures.into_iter().map(|f| ("assertion-failed", f.into_diagnostic())).collect();
+    if let Err(d) = result {
+        problems.push(("evaluation-error", d));
+    }
+    let exit_code = if problems.is_empty() { 0 } else { EVALUATION_ERROR };
+    (problems, exit_code)
+}
+
+fn convert(file: &Path, name: Option<String>) -> Result<(), String> {
+    let source = fs::read_to_string(file).map_err(|e| format!("Cannot read {}: {e}", file.display()))?;
+    let value = match file.extension().and_then(|e| e.to_str()) {
+        Some("json") => input::from_json(&source),
+        Some("toml") => input::from_toml(&source),
+        Some("yaml") | Some("yml") => input::from_yaml(&source),
+        _ => Err("Only .json, .toml, .yaml and .yml files can be converted".to_string())
+    }.map_err(|e| format!("{}: {e}", file.display()))?;
+    let stem = file.file_stem().and_then(|s| s.to_str()).unwrap_or_default();
+    let name = name.unwrap_or_else(|| convert::type_name(stem));
+    write_stdout(&convert::convert(&value, &name, 80)?)
+}
+
+fn format(file: &Path, check: bool, width: usize) -> Result<(), String> {
+    let source = fs::read_to_string(file).map_err(|e| format!("Cannot read {}: {e}", file.display()))?;
+    let e = parser::parse(&source).map_err(|d| ast::report(&file.display().to_string(), &source, &d))?;
+    let tree = cst::parse(&source).map_err(|e| format!("{}: {e}", file.display()))?;
+    let formatted = printer::Printer::new(&source, tree.comments()).print(&e, width);
+    // never write out something that can't be read back
+    if let Err(d) = parser::parse(&formatted) {
+        return Err(format!("Formatting {} produced invalid code, so it was left unchanged:\n{}", file.display(), ast::report("<formatted>", &formatted, &d)));
+    }
+    if check {
+        if formatted != source {
+            eprintln!("{} is not formatted", file.display());
+            std::process::exit(1);
+        }
+    } else if formatted != source {
+        fs::write(file, formatted).map_err(|e| format!("Cannot write {}: {e}", file.display()))?;
+    }
     Ok(())
 }
+
+fn set(file: &Path, path: &str, value: &str) -> Result<(), String> {
+    let source = fs::read_to_string(file).map_err(|e| format!("Cannot read {}: {e}", file.display()))?;
+    parser::parse(&source).map_err(|d| ast::report(&file.display().to_string(), &source, &d))?;
+    let keys = parser::parse_path(path)?;
+    let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+    let edited = edit::set(&source, &keys, value).map_err(|e| format!("{}: {e}", file.display()))?;
+    // the file is only written if it's still valid with the new value
+    let e = parser::parse(&edited).map_err(|d| format!("Setting {path} would make {} invalid:\n{}", file.display(), ast::report(&file.display().to_string(), &edited, &d)))?;
+    typechecker::resolve_aliases(&e, &std::collections::HashMap::new())
+        .map_err(ast::Diagnostic::from)
+        .and_then(|e| typechecker::typecheck(&e, &builtins::types()))
+        .map_err(|d| format!("Setting {path} would make {} invalid:\n{}", file.display(), ast::report(&file.display().to_string(), &edited, &[d])))?;
+    fs::write(file, edited).map_err(|e| format!("Cannot write {}: {e}", file.display()))
+}
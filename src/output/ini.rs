@@ -0,0 +1,97 @@
+use crate::ast::{Value, KeyOrder, RecordMap};
+use crate::output::{PathSegment, SerializeError};
+
+// top level fields that aren't records come before any section,
+// and each top level record becomes a section
+pub fn to_ini(value: &Value, order: KeyOrder) -> Result<String, SerializeError> {
+    let value = value.clone().with_key_order(order);
+    let hm = match &value {
+        Value::Record(hm) => hm,
+        _ => return Err(SerializeError::new(&[], "an INI file must be a record"))
+    };
+    let mut out = String::new();
+    let mut path = Vec::new();
+    write_pairs(&mut out, hm, &mut path, true)?;
+    for (section, v) in hm {
+        if let Value::Record(fields) = v {
+            path.push(PathSegment::Key(section.clone()));
+            check_name(section, &path, "section")?;
+            if !out.is_empty() {
+                out.push('\n');
+            }
+            out.push_str(&format!("[{section}]\n"));
+            write_pairs(&mut out, fields, &mut path, false)?;
+            path.pop();
+        }
+    }
+    Ok(out)
+}
+
+fn write_pairs(out: &mut String, hm: &RecordMap<Value>, path: &mut Vec<PathSegment>, top_level: bool) -> Result<(), SerializeError> {
+    for (k, v) in hm {
+        path.push(PathSegment::Key(k.clone()));
+        let value = match v {
+            Value::Record(_) if top_level => {
+                path.pop();
+                continue;
+            },
+            Value::Record(_) => return Err(SerializeError::new(path, "INI sections can't be nested")),
+            Value::List(_) => return Err(SerializeError::new(path, "INI has no lists")),
+            Value::Null => return Err(SerializeError::new(path, "INI has no null value")),
+            Value::Lambda(..) | Value::Builtin(_) => return Err(SerializeError::new(path, "functions cannot be converted to INI")),
+            Value::Boolean(b) => b.to_string(),
+            Value::Int(n) => n.to_string(),
+            Value::Float(n) => n.to_string(),
+            Value::Text(t) => text(t),
+            Value::Color(c) => c.to_string(),
+            Value::Version(v) => v.to_string()
+        };
+        check_name(k, path, "key")?;
+        out.push_str(&format!("{k} = {value}\n"));
+        path.pop();
+    }
+    Ok(())
+}
+
+fn check_name(name: &str, path: &[PathSegment], what: &str) -> Result<(), SerializeError> {
+    if name.is_empty() || name.chars().any(|c| "=[];#\n\r".contains(c)) {
+        Err(SerializeError::new(path, format!("{name:?} is not a valid INI {what} name")))
+    } else {
+        Ok(())
+    }
+}
+
+// values are quoted when they would otherwise be read differently
+fn text(t: &str) -> String {
+    let plain = !t.starts_with(char::is_whitespace)
+        && !t.ends_with(char::is_whitespace)
+        && !t.chars().any(|c| "\";#\n\r".contains(c));
+    if plain {
+        t.to_string()
+    } else {
+        let escaped = t.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n").replace('\r', "\\r");
+        format!("\"{escaped}\"")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::from_json;
+
+    #[test]
+    fn records_become_sections() {
+        let value = from_json(r#"{ "section": { "key": 1, "on": false }, "name": "x y", "padded": " a", "semi": "a;b" }"#).unwrap();
+        let ini = to_ini(&value, KeyOrder::Insertion).unwrap();
+        assert_eq!(ini, "name = x y\npadded = \" a\"\nsemi = \"a;b\"\n\n[section]\nkey = 1\non = false\n");
+    }
+
+    #[test]
+    fn values_ini_cant_represent_are_errors() {
+        let error = |json: &str| to_ini(&from_json(json).unwrap(), KeyOrder::Insertion).unwrap_err().to_string();
+        assert_eq!(error(r#"{ "a": { "b": { "c": 1 } } }"#), "Cannot serialize a.b: INI sections can't be nested");
+        assert_eq!(error(r#"{ "a": [1] }"#), "Cannot serialize a: INI has no lists");
+        assert_eq!(error(r#"{ "a=b": 1 }"#), "Cannot serialize a=b: \"a=b\" is not a valid INI key name");
+        assert_eq!(error(r#"{ "[a]": {} }"#), "Cannot serialize [a]: \"[a]\" is not a valid INI section name");
+    }
+}
@@ -0,0 +1,115 @@
+use crate::ast::{Value, KeyOrder};
+use crate::output::{PathSegment, SerializeError};
+
+pub fn to_json(value: &Value, pretty: bool, order: KeyOrder) -> Result<String, SerializeError> {
+    let value = value.clone().with_key_order(order);
+    let mut out = String::new();
+    let mut path = Vec::new();
+    write_value(&mut out, &value, pretty, 0, &mut path)?;
+    Ok(out)
+}
+
+fn write_value(out: &mut String, value: &Value, pretty: bool, depth: usize, path: &mut Vec<PathSegment>) -> Result<(), SerializeError> {
+    match value {
+        Value::Null => out.push_str("null"),
+        Value::Boolean(b) => out.push_str(&b.to_string()),
+        Value::Int(n) => out.push_str(&n.to_string()),
+        Value::Float(n) if n.is_finite() => out.push_str(&format!("{n:?}")),
+        Value::Float(n) => return Err(SerializeError::new(path, format!("{n} is not a valid JSON number"))),
+        Value::Text(t) => write_string(out, t),
+        Value::Color(c) => write_string(out, &c.to_string()),
+        Value::Version(v) => write_string(out, &v.to_string()),
+        Value::Lambda(..) | Value::Builtin(_) => return Err(SerializeError::new(path, "functions cannot be converted to JSON")),
+        Value::List(l) if l.is_empty() => out.push_str("[]"),
+        Value::List(l) => {
+            out.push('[');
+            for (i, v) in l.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                newline(out, pretty, depth + 1);
+                path.push(PathSegment::Index(i));
+                write_value(out, v, pretty, depth + 1, path)?;
+                path.pop();
+            }
+            newline(out, pretty, depth);
+            out.push(']');
+        },
+        Value::Record(hm) if hm.is_empty() => out.push_str("{}"),
+        Value::Record(hm) => {
+            out.push('{');
+            for (i, (k, v)) in hm.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                newline(out, pretty, depth + 1);
+                write_string(out, k);
+                out.push_str(if pretty { ": " } else { ":" });
+                path.push(PathSegment::Key(k.clone()));
+                write_value(out, v, pretty, depth + 1, path)?;
+                path.pop();
+            }
+            newline(out, pretty, depth);
+            out.push('}');
+        }
+    }
+    Ok(())
+}
+
+fn newline(out: &mut String, pretty: bool, depth: usize) {
+    if pretty {
+        out.push('\n');
+        out.push_str(&"  ".repeat(depth));
+    }
+}
+
+pub fn write_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\u{08}' => out.push_str("\\b"),
+            '\u{0c}' => out.push_str("\\f"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c)
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::from_json;
+
+    #[test]
+    fn compact_output_has_no_whitespace() {
+        let value = from_json(r#"{ "b": [1, 2.5, null], "a": { "c": true }, "e": [], "f": {} }"#).unwrap();
+        assert_eq!(to_json(&value, false, KeyOrder::Insertion).unwrap(), r#"{"b":[1,2.5,null],"a":{"c":true},"e":[],"f":{}}"#);
+        assert_eq!(to_json(&value, false, KeyOrder::Sorted).unwrap(), r#"{"a":{"c":true},"b":[1,2.5,null],"e":[],"f":{}}"#);
+    }
+
+    #[test]
+    fn pretty_output_indents_by_two() {
+        let value = from_json(r#"{ "a": [1], "b": {} }"#).unwrap();
+        assert_eq!(to_json(&value, true, KeyOrder::Insertion).unwrap(), "{\n  \"a\": [\n    1\n  ],\n  \"b\": {}\n}");
+    }
+
+    #[test]
+    fn text_round_trips() {
+        let value = Value::List(["quote \" and \\", "line\nbreak\ttab", "\u{1}\u{8}\u{c}", "ünïcode 😀"].iter().map(|t| Value::Text(t.to_string())).collect());
+        for pretty in [true, false] {
+            assert_eq!(from_json(&to_json(&value, pretty, KeyOrder::Insertion).unwrap()).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn infinite_numbers_are_errors() {
+        let value = Value::Record([("a".to_string(), Value::List(vec![Value::Float(f64::INFINITY)]))].into_iter().collect());
+        assert_eq!(to_json(&value, true, KeyOrder::Insertion).unwrap_err().to_string(), "Cannot serialize a[0]: inf is not a valid JSON number");
+    }
+}
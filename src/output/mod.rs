@@ -0,0 +1,66 @@
+use std::fmt;
+use crate::ast::{Value, KeyOrder};
+use crate::cli::OutputFormat;
+
+pub mod json;
+pub mod yaml;
+pub mod toml;
+pub mod ini;
+pub mod shell;
+
+// where in a value serialization failed, e.g. servers[0].port
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathSegment {
+    Key(String),
+    Index(usize)
+}
+
+#[derive(Debug, Clone)]
+pub struct SerializeError {
+    pub path: Vec<PathSegment>,
+    pub message: String
+}
+
+impl SerializeError {
+    pub fn new(path: &[PathSegment], message: impl Into<String>) -> SerializeError {
+        SerializeError { path: path.to_vec(), message: message.into() }
+    }
+}
+
+pub fn display_path(path: &[PathSegment]) -> String {
+    if path.is_empty() {
+        return "the top level value".to_string();
+    }
+    let mut s = String::new();
+    for segment in path {
+        match segment {
+            PathSegment::Key(k) if s.is_empty() => s.push_str(k),
+            PathSegment::Key(k) => {
+                s.push('.');
+                s.push_str(k);
+            },
+            PathSegment::Index(i) => s.push_str(&format!("[{i}]"))
+        }
+    }
+    s
+}
+
+impl fmt::Display for SerializeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Cannot serialize {}: {}", display_path(&self.path), self.message)
+    }
+}
+
+// a value in any of the output formats. pretty only affects JSON
+pub fn render(value: &Value, format: OutputFormat, pretty: bool, order: KeyOrder) -> Result<String, SerializeError> {
+    Ok(match format {
+        OutputFormat::Debug => format!("{:#?}\n", value.clone().with_key_order(order)),
+        OutputFormat::Json => json::to_json(value, pretty, order)? + "\n",
+        OutputFormat::Yaml => yaml::to_yaml(value, order)?,
+        OutputFormat::Toml => toml::to_toml(value, order)?,
+        OutputFormat::Ini => ini::to_ini(value, order)?,
+        OutputFormat::Bash => shell::to_shell(value, shell::Shell::Bash, order)?,
+        OutputFormat::Zsh => shell::to_shell(value, shell::Shell::Zsh, order)?,
+        OutputFormat::Fish => shell::to_shell(value, shell::Shell::Fish, order)?
+    })
+}
@@ -0,0 +1,206 @@
+use crate::ast::{Value, KeyOrder, RecordMap};
+use crate::output::{PathSegment, SerializeError};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish
+}
+
+// Renders shell configuration from a record with any of these fields:
+//   aliases = { name = "command", ... }
+//   env = { NAME = value, ... }  (null unsets the variable, lists are joined with ':')
+//   path = ["dir", ...]          (prepended to PATH, in order)
+//   functions = { name = "body", ... }
+// other fields are ignored, so a module's output can be passed in directly
+pub fn to_shell(value: &Value, shell: Shell, order: KeyOrder) -> Result<String, SerializeError> {
+    let value = value.clone().with_key_order(order);
+    let hm = match &value {
+        Value::Record(hm) => hm,
+        _ => return Err(SerializeError::new(&[], "shell configuration must be a record"))
+    };
+    let mut out = String::new();
+    if let Some(env) = hm.get("env") {
+        let env = record(env, "env")?;
+        for (name, v) in env {
+            let path = [PathSegment::Key("env".to_string()), PathSegment::Key(name.clone())];
+            if !is_var_name(name) {
+                return Err(SerializeError::new(&path, format!("{name:?} is not a valid environment variable name")));
+            }
+            match (shell, env_value(v, &path)?) {
+                (Shell::Fish, None) => out.push_str(&format!("set -e {name}\n")),
+                (_, None) => out.push_str(&format!("unset {name}\n")),
+                (Shell::Fish, Some(v)) => out.push_str(&format!("set -gx {name} {}\n", quote(&v, shell))),
+                (_, Some(v)) => out.push_str(&format!("export {name}={}\n", quote(&v, shell)))
+            }
+        }
+    }
+    if let Some(dirs) = hm.get("path") {
+        let dirs = match dirs {
+            Value::List(l) => l,
+            _ => return Err(SerializeError::new(&[PathSegment::Key("path".to_string())], "path must be a list of directories"))
+        };
+        // prepend in reverse so that the first directory ends up first
+        for (i, dir) in dirs.iter().enumerate().rev() {
+            let dir = match dir {
+                Value::Text(t) => path_word(t, shell),
+                _ => return Err(SerializeError::new(&[PathSegment::Key("path".to_string()), PathSegment::Index(i)], "directories must be text"))
+            };
+            match shell {
+                Shell::Fish => out.push_str(&format!("set -gx PATH {dir} $PATH\n")),
+                _ => out.push_str(&format!("export PATH={dir}:\"$PATH\"\n"))
+            }
+        }
+    }
+    if let Some(aliases) = hm.get("aliases") {
+        for (name, command) in record(aliases, "aliases")? {
+            let path = [PathSegment::Key("aliases".to_string()), PathSegment::Key(name.clone())];
+            if !is_alias_name(name) {
+                return Err(SerializeError::new(&path, format!("{name:?} is not a valid alias name")));
+            }
+            let command = text(command, &path)?;
+            match shell {
+                Shell::Fish => out.push_str(&format!("alias {name} {}\n", quote(command, shell))),
+                _ => out.push_str(&format!("alias {name}={}\n", quote(command, shell)))
+            }
+        }
+    }
+    if let Some(functions) = hm.get("functions") {
+        for (name, body) in record(functions, "functions")? {
+            let path = [PathSegment::Key("functions".to_string()), PathSegment::Key(name.clone())];
+            if !is_alias_name(name) {
+                return Err(SerializeError::new(&path, format!("{name:?} is not a valid function name")));
+            }
+            let body: String = text(body, &path)?.lines().map(|l| format!("    {l}\n")).collect();
+            match shell {
+                Shell::Fish => out.push_str(&format!("function {name}\n{body}end\n")),
+                _ => out.push_str(&format!("{name}() {{\n{body}}}\n"))
+            }
+        }
+    }
+    Ok(out)
+}
+
+fn record<'a>(v: &'a Value, field: &str) -> Result<&'a RecordMap<Value>, SerializeError> {
+    match v {
+        Value::Record(hm) => Ok(hm),
+        _ => Err(SerializeError::new(&[PathSegment::Key(field.to_string())], format!("{field} must be a record")))
+    }
+}
+
+fn text<'a>(v: &'a Value, path: &[PathSegment]) -> Result<&'a str, SerializeError> {
+    match v {
+        Value::Text(t) => Ok(t),
+        _ => Err(SerializeError::new(path, "expected text"))
+    }
+}
+
+fn env_value(v: &Value, path: &[PathSegment]) -> Result<Option<String>, SerializeError> {
+    match v {
+        Value::Null => Ok(None),
+        Value::Text(t) => Ok(Some(t.clone())),
+        Value::Color(c) => Ok(Some(c.to_string())),
+        Value::Version(v) => Ok(Some(v.to_string())),
+        Value::Int(n) => Ok(Some(n.to_string())),
+        Value::Float(n) => Ok(Some(n.to_string())),
+        Value::Boolean(b) => Ok(Some(b.to_string())),
+        Value::List(l) => {
+            let mut parts = Vec::new();
+            for (i, v) in l.iter().enumerate() {
+                let mut path = path.to_vec();
+                path.push(PathSegment::Index(i));
+                match env_value(v, &path)? {
+                    Some(part) if !matches!(v, Value::List(_)) => parts.push(part),
+                    _ => return Err(SerializeError::new(&path, "list elements of environment variables must be text, numbers or booleans"))
+                }
+            }
+            Ok(Some(parts.join(":")))
+        },
+        _ => Err(SerializeError::new(path, "environment variables must be text, numbers, booleans, lists or null"))
+    }
+}
+
+pub fn is_var_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn is_alias_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || "_-.:+@".contains(c))
+}
+
+// a directory with a leading ~ still expands to the home directory
+fn path_word(dir: &str, shell: Shell) -> String {
+    match dir.strip_prefix('~') {
+        Some("") => "\"$HOME\"".to_string(),
+        Some(rest) if rest.starts_with('/') => format!("\"$HOME\"{}", quote(rest, shell)),
+        _ => quote(dir, shell)
+    }
+}
+
+// quotes a word so the shell reads it back exactly as written.
+// words made only of safe characters are left alone
+pub fn quote(s: &str, shell: Shell) -> String {
+    if !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || "_@%+=:,./-".contains(c)) {
+        return s.to_string();
+    }
+    match shell {
+        // fish understands \' and \\ inside single quotes
+        Shell::Fish => format!("'{}'", s.replace('\\', "\\\\").replace('\'', "\\'")),
+        // in POSIX shells nothing can be escaped inside single quotes, so close the quotes around an escaped '
+        Shell::Bash | Shell::Zsh => format!("'{}'", s.replace('\'', "'\\''"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::from_json;
+
+    fn config() -> Value {
+        from_json(r#"{
+            "env": { "EDITOR": "vim", "PAGER": null, "DIRS": ["a", "b c"] },
+            "path": ["~/bin", "/opt/it's"],
+            "aliases": { "ll": "ls -l" },
+            "functions": { "mkcd": "mkdir -p \"$1\"\ncd \"$1\"" },
+            "other": 1
+        }"#).unwrap()
+    }
+
+    #[test]
+    fn posix_shells() {
+        let out = to_shell(&config(), Shell::Bash, KeyOrder::Insertion).unwrap();
+        assert_eq!(out, concat!(
+            "export EDITOR=vim\n", "unset PAGER\n", "export DIRS='a:b c'\n",
+            "export PATH='/opt/it'\\''s':\"$PATH\"\n", "export PATH=\"$HOME\"/bin:\"$PATH\"\n",
+            "alias ll='ls -l'\n", "mkcd() {\n    mkdir -p \"$1\"\n    cd \"$1\"\n}\n"
+        ));
+    }
+
+    #[test]
+    fn fish() {
+        let out = to_shell(&config(), Shell::Fish, KeyOrder::Insertion).unwrap();
+        assert_eq!(out, concat!(
+            "set -gx EDITOR vim\n", "set -e PAGER\n", "set -gx DIRS 'a:b c'\n",
+            "set -gx PATH '/opt/it\\'s' $PATH\n", "set -gx PATH \"$HOME\"/bin $PATH\n",
+            "alias ll 'ls -l'\n", "function mkcd\n    mkdir -p \"$1\"\n    cd \"$1\"\nend\n"
+        ));
+    }
+
+    #[test]
+    fn quoted_words_read_back_the_same() {
+        for word in ["plain", "", "two words", "it's", "'", "\\n", "$HOME", "a\"b", "tab\there", "line\nbreak", "*"] {
+            let output = std::process::Command::new("sh").arg("-c").arg(format!("printf %s {}", quote(word, Shell::Bash))).output().unwrap();
+            assert_eq!(String::from_utf8(output.stdout).unwrap(), word);
+        }
+    }
+
+    #[test]
+    fn invalid_names_are_errors() {
+        let error = |json: &str| to_shell(&from_json(json).unwrap(), Shell::Bash, KeyOrder::Insertion).unwrap_err().to_string();
+        assert_eq!(error(r#"{ "env": { "1A": "x" } }"#), "Cannot serialize env.1A: \"1A\" is not a valid environment variable name");
+        assert_eq!(error(r#"{ "aliases": { "a b": "x" } }"#), "Cannot serialize aliases.a b: \"a b\" is not a valid alias name");
+        assert_eq!(error(r#"{ "env": { "A": [[1]] } }"#), "Cannot serialize env.A[0]: list elements of environment variables must be text, numbers or booleans");
+    }
+}
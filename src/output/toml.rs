@@ -0,0 +1,197 @@
+use crate::ast::{Value, KeyOrder, RecordMap};
+use crate::output::{PathSegment, SerializeError};
+
+pub fn to_toml(value: &Value, order: KeyOrder) -> Result<String, SerializeError> {
+    let value = value.clone().with_key_order(order);
+    match &value {
+        Value::Record(hm) => {
+            let mut out = String::new();
+            write_table(&mut out, hm, &[], &mut Vec::new())?;
+            Ok(out)
+        },
+        _ => Err(SerializeError::new(&[], "a TOML document must be a record"))
+    }
+}
+
+// key/value pairs come first, then sub-tables, then arrays of tables, as TOML requires
+fn write_table(out: &mut String, hm: &RecordMap<Value>, keys: &[String], path: &mut Vec<PathSegment>) -> Result<(), SerializeError> {
+    for (k, v) in hm {
+        if matches!(v, Value::Record(_)) || is_array_of_tables(v) {
+            continue;
+        }
+        path.push(PathSegment::Key(k.clone()));
+        out.push_str(&key(k));
+        out.push_str(" = ");
+        write_inline(out, v, path)?;
+        out.push('\n');
+        path.pop();
+    }
+    for (k, v) in hm {
+        if let Value::Record(sub) = v {
+            path.push(PathSegment::Key(k.clone()));
+            let mut sub_keys = keys.to_vec();
+            sub_keys.push(k.clone());
+            header(out, &format!("[{}]", dotted(&sub_keys)));
+            write_table(out, sub, &sub_keys, path)?;
+            path.pop();
+        }
+    }
+    for (k, v) in hm {
+        if let (true, Value::List(l)) = (is_array_of_tables(v), v) {
+            path.push(PathSegment::Key(k.clone()));
+            let mut sub_keys = keys.to_vec();
+            sub_keys.push(k.clone());
+            for (i, table) in l.iter().enumerate() {
+                if let Value::Record(sub) = table {
+                    path.push(PathSegment::Index(i));
+                    header(out, &format!("[[{}]]", dotted(&sub_keys)));
+                    write_table(out, sub, &sub_keys, path)?;
+                    path.pop();
+                }
+            }
+            path.pop();
+        }
+    }
+    Ok(())
+}
+
+fn header(out: &mut String, header: &str) {
+    if !out.is_empty() {
+        out.push('\n');
+    }
+    out.push_str(header);
+    out.push('\n');
+}
+
+fn is_array_of_tables(v: &Value) -> bool {
+    match v {
+        Value::List(l) => !l.is_empty() && l.iter().all(|v| matches!(v, Value::Record(_))),
+        _ => false
+    }
+}
+
+fn write_inline(out: &mut String, value: &Value, path: &mut Vec<PathSegment>) -> Result<(), SerializeError> {
+    match value {
+        Value::Null => return Err(SerializeError::new(path, "TOML has no null value")),
+        Value::Lambda(..) | Value::Builtin(_) => return Err(SerializeError::new(path, "functions cannot be converted to TOML")),
+        Value::Boolean(b) => out.push_str(&b.to_string()),
+        Value::Int(n) => out.push_str(&n.to_string()),
+        Value::Float(n) if n.is_nan() => out.push_str("nan"),
+        Value::Float(n) if n.is_infinite() => out.push_str(if *n > 0.0 { "inf" } else { "-inf" }),
+        Value::Float(n) => out.push_str(&format!("{n:?}")),
+        Value::Text(t) => write_string(out, t),
+        Value::Color(c) => write_string(out, &c.to_string()),
+        Value::Version(v) => write_string(out, &v.to_string()),
+        Value::List(l) => {
+            if let Some(first) = l.first() {
+                if let Some(i) = l.iter().position(|v| kind(v) != kind(first)) {
+                    path.push(PathSegment::Index(i));
+                    let err = SerializeError::new(path, format!("TOML arrays can't mix types, this element is {} but the first is {}", kind(&l[i]), kind(first)));
+                    path.pop();
+                    return Err(err);
+                }
+            }
+            out.push('[');
+            for (i, v) in l.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                path.push(PathSegment::Index(i));
+                write_inline(out, v, path)?;
+                path.pop();
+            }
+            out.push(']');
+        },
+        // only records nested inside other arrays need to be inline tables
+        Value::Record(hm) => {
+            out.push('{');
+            for (i, (k, v)) in hm.iter().enumerate() {
+                out.push_str(if i > 0 { ", " } else { " " });
+                out.push_str(&key(k));
+                out.push_str(" = ");
+                path.push(PathSegment::Key(k.clone()));
+                write_inline(out, v, path)?;
+                path.pop();
+            }
+            out.push_str(if hm.is_empty() { "}" } else { " }" });
+        }
+    }
+    Ok(())
+}
+
+fn kind(v: &Value) -> &'static str {
+    match v {
+        Value::Null => "null",
+        Value::Boolean(_) => "a boolean",
+        Value::Int(_) => "an integer",
+        Value::Float(_) => "a float",
+        // colors and versions are written as strings
+        Value::Text(_) | Value::Color(_) | Value::Version(_) => "a string",
+        Value::List(_) => "an array",
+        Value::Record(_) => "a table",
+        Value::Lambda(..) | Value::Builtin(_) => "a function"
+    }
+}
+
+fn dotted(keys: &[String]) -> String {
+    keys.iter().map(|k| key(k)).collect::<Vec<String>>().join(".")
+}
+
+// bare keys are only allowed to contain letters, digits, dashes and underscores
+fn key(k: &str) -> String {
+    if !k.is_empty() && k.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        k.to_string()
+    } else {
+        let mut out = String::new();
+        write_string(&mut out, k);
+        out
+    }
+}
+
+fn write_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\u{08}' => out.push_str("\\b"),
+            '\u{0c}' => out.push_str("\\f"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04X}", c as u32)),
+            c => out.push(c)
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::{from_json, from_toml};
+
+    #[test]
+    fn tables_come_after_the_pairs() {
+        let value = from_json(r#"{ "server": { "host": "h", "a b": 1 }, "users": [{ "name": "u" }], "name": "x", "tags": ["a"] }"#).unwrap();
+        let toml = to_toml(&value, KeyOrder::Insertion).unwrap();
+        assert_eq!(toml, "name = \"x\"\ntags = [\"a\"]\n\n[server]\nhost = \"h\"\n\"a b\" = 1\n\n[[users]]\nname = \"u\"\n");
+    }
+
+    #[test]
+    fn values_round_trip() {
+        let value = from_json(r#"{
+            "a": 1, "b": -0.5, "c": "quote \" \\ \n \u0001", "d": [[1, 2], ["x"]], "e": [{ "f": [{ "g": true }] }],
+            "h": { "i": { "j": {} } }, "k": [{ "l": 1 }, { "m": { "n": 2 } }]
+        }"#).unwrap();
+        assert_eq!(from_toml(&to_toml(&value, KeyOrder::Insertion).unwrap()).unwrap(), value);
+    }
+
+    #[test]
+    fn values_toml_cant_represent_are_errors() {
+        let error = |json: &str| to_toml(&from_json(json).unwrap(), KeyOrder::Insertion).unwrap_err().to_string();
+        assert_eq!(error(r#"{ "a": { "b": null } }"#), "Cannot serialize a.b: TOML has no null value");
+        assert_eq!(error(r#"{ "a": [1, "b"] }"#), "Cannot serialize a[1]: TOML arrays can't mix types, this element is a string but the first is an integer");
+        assert_eq!(error("[]"), "Cannot serialize the top level value: a TOML document must be a record");
+    }
+}
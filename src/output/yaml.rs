@@ -0,0 +1,158 @@
+use crate::ast::{Value, KeyOrder};
+use crate::output::{PathSegment, SerializeError};
+use crate::output::json::write_string;
+
+pub fn to_yaml(value: &Value, order: KeyOrder) -> Result<String, SerializeError> {
+    let value = value.clone().with_key_order(order);
+    let mut out = String::new();
+    let mut path = Vec::new();
+    write_block(&mut out, &value, 0, &mut path)?;
+    Ok(out)
+}
+
+// writes a value on its own lines, each starting at the given indentation
+fn write_block(out: &mut String, value: &Value, indent: usize, path: &mut Vec<PathSegment>) -> Result<(), SerializeError> {
+    match value {
+        Value::Record(hm) if !hm.is_empty() => {
+            for (k, v) in hm {
+                out.push_str(&" ".repeat(indent));
+                write_text(out, k);
+                out.push(':');
+                path.push(PathSegment::Key(k.clone()));
+                write_nested(out, v, indent + 2, path)?;
+                path.pop();
+            }
+        },
+        Value::List(l) if !l.is_empty() => {
+            for (i, v) in l.iter().enumerate() {
+                out.push_str(&" ".repeat(indent));
+                out.push('-');
+                path.push(PathSegment::Index(i));
+                if is_collection(v) {
+                    // the first line of the item goes on the same line as the dash
+                    let mut item = String::new();
+                    write_block(&mut item, v, indent + 2, path)?;
+                    out.push(' ');
+                    out.push_str(&item[indent + 2..]);
+                } else {
+                    out.push(' ');
+                    write_scalar(out, v, path)?;
+                    out.push('\n');
+                }
+                path.pop();
+            }
+        },
+        v => {
+            write_scalar(out, v, path)?;
+            out.push('\n');
+        }
+    }
+    Ok(())
+}
+
+// writes the value of a record field, after the colon
+fn write_nested(out: &mut String, value: &Value, indent: usize, path: &mut Vec<PathSegment>) -> Result<(), SerializeError> {
+    if is_collection(value) {
+        out.push('\n');
+        write_block(out, value, indent, path)
+    } else {
+        out.push(' ');
+        write_scalar(out, value, path)?;
+        out.push('\n');
+        Ok(())
+    }
+}
+
+fn is_collection(value: &Value) -> bool {
+    match value {
+        Value::Record(hm) => !hm.is_empty(),
+        Value::List(l) => !l.is_empty(),
+        _ => false
+    }
+}
+
+fn write_scalar(out: &mut String, value: &Value, path: &[PathSegment]) -> Result<(), SerializeError> {
+    match value {
+        Value::Null => out.push_str("null"),
+        Value::Boolean(b) => out.push_str(&b.to_string()),
+        Value::Int(n) => out.push_str(&n.to_string()),
+        Value::Float(n) if n.is_nan() => out.push_str(".nan"),
+        Value::Float(n) if n.is_infinite() => out.push_str(if *n > 0.0 { ".inf" } else { "-.inf" }),
+        Value::Float(n) => out.push_str(&format!("{n:?}")),
+        Value::Text(t) => write_text(out, t),
+        Value::Color(c) => write_text(out, &c.to_string()),
+        Value::Version(v) => write_text(out, &v.to_string()),
+        Value::Record(_) => out.push_str("{}"),
+        Value::List(_) => out.push_str("[]"),
+        Value::Lambda(..) | Value::Builtin(_) => return Err(SerializeError::new(path, "functions cannot be converted to YAML"))
+    }
+    Ok(())
+}
+
+// plain scalars are used where they can't be mistaken for anything else
+fn write_text(out: &mut String, t: &str) {
+    if needs_quotes(t) {
+        // JSON strings are valid double quoted YAML scalars
+        write_string(out, t);
+    } else {
+        out.push_str(t);
+    }
+}
+
+// YAML 1.1 and 1.2 read some plain scalars as something other than text, and readers differ on which,
+// so anything any of them would read as null, a bool or a number is quoted
+fn needs_quotes(t: &str) -> bool {
+    const RESERVED: [&str; 14] = ["null", "~", "true", "false", "yes", "no", "y", "n", "on", "off", ".inf", ".nan", "+.inf", "-.inf"];
+    let unsigned = t.strip_prefix(['+', '-']).unwrap_or(t);
+    t.is_empty()
+        || RESERVED.contains(&t.to_lowercase().as_str())
+        // numbers in any base, with _ separators, exponents or YAML 1.1's base 60, and timestamps
+        || unsigned.starts_with(|c: char| c.is_ascii_digit())
+        || t.starts_with(|c: char| c.is_whitespace() || "-?:,[]{}#&*!|>'\"%@`.".contains(c))
+        || t.ends_with(|c: char| c.is_whitespace() || c == ':')
+        || t.contains(": ")
+        || t.contains(" #")
+        || t.chars().any(|c| c.is_control())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::RecordMap;
+    use crate::input::from_yaml;
+
+    #[test]
+    fn quotes_text_that_reads_as_another_type() {
+        let texts = [
+            "", "null", "Null", "~", "true", "FALSE", "yes", "No", "Y", "n", "on", "OFF", "oN",
+            "1", "-1", "+1", "1.5", "1e3", "0x1F", "0o17", "017", "0b101", "1_000", "190:20:30",
+            ".inf", "-.Inf", "+.INF", ".NaN", "2001-12-14"
+        ];
+        for t in texts {
+            assert!(needs_quotes(t), "{t} should be quoted");
+        }
+        for t in ["hello", "yesterday", "on-call", "offline", "a1", "n/a"] {
+            assert!(!needs_quotes(t), "{t} shouldn't be quoted");
+        }
+    }
+
+    #[test]
+    fn text_round_trips() {
+        let texts = ["yes", "0x1F", "1_000", ".nan", "1.5", "", "- a", "a: b", "line\nbreak", "plain text", "\u{7}"];
+        let mut record = RecordMap::new();
+        for (i, t) in texts.iter().enumerate() {
+            record.insert(format!("k{i}"), Value::Text(t.to_string()));
+        }
+        record.insert("list".to_string(), Value::List(texts.iter().map(|t| Value::Text(t.to_string())).collect()));
+        let value = Value::Record(record);
+        let yaml = to_yaml(&value, KeyOrder::Insertion).unwrap();
+        assert_eq!(from_yaml(&yaml).unwrap(), value);
+    }
+
+    #[test]
+    fn scalars_round_trip() {
+        let value = Value::List(vec![Value::Null, Value::Boolean(true), Value::Int(-3), Value::Float(0.5), Value::Float(1e300)]);
+        let yaml = to_yaml(&value, KeyOrder::Insertion).unwrap();
+        assert_eq!(from_yaml(&yaml).unwrap(), value);
+    }
+}
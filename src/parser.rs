@@ -1,7 +1,7 @@
-use pest::Parser;
-use std::collections::HashMap;
-use pest::iterators::Pair;
-use crate::ast::{Expr, ExprKind::*, Type, Ident, Bop, Uop};
+use pest::Parser as _;
+use crate::cst::CstNode;
+use crate::recovery;
+use crate::ast::{Diagnostic, RecordMap, Expr, ExprKind::*, Type, Ident, Bop, Uop, Span, Refinement, Color};
 
 #[derive(Parser)]
 #[grammar = "grammar.pest"]
@@ -11,44 +11,149 @@ pub struct GrammarParser;
 //     println!("{:?}", Rule);
 // }
 
-pub fn parse(source: &str) -> std::result::Result<Expr, pest::error::Error<Rule>> {
-    let file = GrammarParser::parse(Rule::file, source)?.next().unwrap();
-    Ok(parse_expr(file))
+// every syntax error in the file is reported, not just the first
+pub fn parse(source: &str) -> std::result::Result<Expr, Vec<Diagnostic>> {
+    recovery::parse(source)
+}
+
+// the AST of a whole file. Errors are literals that the grammar accepts but that don't make sense, like numbers that are too large
+pub fn lower(file: &CstNode) -> Result<Expr, Diagnostic> {
+    // each type declaration scopes over everything after it
+    let mut it = file.children().collect::<Vec<&CstNode>>().into_iter().rev();
+    let mut e = parse_expr(next(&mut it, file)?)?;
+    for decl in it {
+        let span = decl.span();
+        let mut inner = decl.children();
+        let name = parse_ident(next(&mut inner, decl)?);
+        let t = parse_type(next(&mut inner, decl)?)?;
+        e = Expr {
+            t: None,
+            span: Span { start: span.start, end: e.span.end },
+            expr: TypeDecl(name, t, Box::new(e))
+        };
+    }
+    Ok(e)
+}
+
+// a node the grammar shouldn't be able to produce where it was found. This is a bug in the parser, not the source
+fn malformed(pair: &CstNode) -> Diagnostic {
+    Diagnostic::new(pair.span(), &format!("the parser couldn't make sense of this {:?}", pair.as_rule()), None)
+}
+
+// the next child of a node, which the grammar says is there
+fn next<'t, 'a: 't>(it: &mut impl Iterator<Item = &'t CstNode<'a>>, parent: &CstNode) -> Result<&'t CstNode<'a>, Diagnostic> {
+    it.next().ok_or_else(|| malformed(parent))
 }
 
 // TODO: add the rules for parsing types
 
-fn parse_ident(pair: Pair<Rule>) -> Ident {
+fn parse_ident(pair: &CstNode) -> Ident {
     pair.as_str().to_string()
 }
 
-fn parse_type(pair: Pair<Rule>) -> Type {
+// record keys are either identifiers or strings
+pub fn parse_key(pair: &CstNode) -> Result<Ident, Diagnostic> {
     match pair.as_rule() {
+        Rule::string => string(pair),
+        _ => Ok(parse_ident(pair))
+    }
+}
+
+// a dotted path through records, like a."b.c"
+pub fn parse_path(path: &str) -> Result<Vec<Ident>, String> {
+    let pairs = GrammarParser::parse(Rule::field_path, path).map_err(|e| format!("Invalid path {path}:\n{e}"))?;
+    pairs.filter(|p| p.as_rule() != Rule::EOI).map(|p| match p.as_rule() {
+        Rule::string => {
+            let inner = p.into_inner().as_str();
+            unescape(inner).map_err(|(start, end)| format!("Invalid path {path}: {} is not a character", &inner[start..end]))
+        },
+        _ => Ok(p.as_str().to_string())
+    }).collect()
+}
+
+// the text of a string literal
+fn string(pair: &CstNode) -> Result<String, Diagnostic> {
+    let inner = next(&mut pair.children(), pair)?;
+    let start = inner.span().start;
+    unescape(inner.as_str()).map_err(|(s, e)| {
+        let span = Span { start: start + s, end: start + e };
+        let hint = "a surrogate, from \\ud800 to \\udfff, has to be half of a pair, like \\ud83d\\ude00";
+        Diagnostic::new(span, &format!("{} is not a character", &inner.as_str()[s..e]), Some(hint))
+    })
+}
+
+// the grammar only allows escape sequences that are well formed, but a \u escape can be a surrogate, which is only
+// a character as the first half of a pair followed by the second half, like in JSON. Otherwise this gives where it is
+fn unescape(s: &str) -> Result<String, (usize, usize)> {
+    let mut out = String::new();
+    let mut chars = s.char_indices();
+    // a \u escape at i
+    let code = |i: usize| s.get(i + 2..i + 6).and_then(|hex| u32::from_str_radix(hex, 16).ok());
+    while let Some((i, c)) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some((_, 'b')) => out.push('\u{08}'),
+            Some((_, 'f')) => out.push('\u{0c}'),
+            Some((_, 'n')) => out.push('\n'),
+            Some((_, 'r')) => out.push('\r'),
+            Some((_, 't')) => out.push('\t'),
+            Some((_, 'u')) => {
+                let high = code(i).ok_or((i, i + 2))?;
+                // the second half of a pair is another \u escape straight after
+                let low = code(i + 6).filter(|low| s[i + 6..].starts_with("\\u") && (0xd800..0xdc00).contains(&high) && (0xdc00..0xe000).contains(low));
+                let (c, len) = match low {
+                    Some(low) => (char::from_u32(0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)), 12),
+                    None => (char::from_u32(high), 6)
+                };
+                out.push(c.ok_or((i, i + 6))?);
+                // the rest of the escape, after the u
+                chars.nth(len - 3);
+            },
+            Some((_, c)) => out.push(c),
+            None => ()
+        }
+    }
+    Ok(out)
+}
+
+fn parse_type(pair: &CstNode) -> Result<Type, Diagnostic> {
+    Ok(match pair.as_rule() {
         Rule::function_type => {
-            let mut it = pair.into_inner();
-            let t1 = parse_type(it.next().unwrap());
-            let t2 = parse_type(it.next().unwrap());
+            let mut it = pair.children();
+            let t1 = parse_type(next(&mut it, pair)?)?;
+            let t2 = parse_type(next(&mut it, pair)?)?;
             Type::Function(Box::new(t1), Box::new(t2))
         },
         Rule::list_type => {
-            let t = parse_type(pair.into_inner().next().unwrap());
+            let t = parse_type(next(&mut pair.children(), pair)?)?;
             Type::List(Box::new(t))
         },
         Rule::record_type => {
-            let i = pair.into_inner();
-            let mut hashmap: HashMap<Ident, Type>= HashMap::new();
+            let i = pair.children();
+            let mut hashmap: RecordMap<Type> = RecordMap::new();
             for record_pair in i {
-                let mut inner_rules = record_pair.into_inner();
-                let k = parse_ident(inner_rules.next().unwrap());
-                let v = parse_type(inner_rules.next().unwrap());
+                let mut inner_rules = record_pair.children();
+                let k = parse_key(next(&mut inner_rules, record_pair)?)?;
+                let mut v = parse_type(next(&mut inner_rules, record_pair)?)?;
+                if let Some(refinement) = inner_rules.next() {
+                    let pred = parse_expr(next(&mut refinement.children(), refinement)?)?;
+                    v = Type::Refined(Box::new(v), Refinement { binder: k.clone(), pred: Box::new(pred) });
+                }
                 hashmap.insert(k, v);
             }
             Type::Record(hashmap)
         },
+        Rule::optional_type => {
+            let t = parse_type(next(&mut pair.children(), pair)?)?;
+            Type::Alternative(Box::new(t), Box::new(Type::Null))
+        },
         Rule::alternative_type => {
-            let mut it = pair.into_inner();
-            let t1 = parse_type(it.next().unwrap());
-            let t2 = parse_type(it.next().unwrap());
+            let mut it = pair.children();
+            let t1 = parse_type(next(&mut it, pair)?)?;
+            let t2 = parse_type(next(&mut it, pair)?)?;
             Type::Alternative(Box::new(t1), Box::new(t2))
         },
         Rule::user_type => {
@@ -63,212 +168,389 @@ fn parse_type(pair: Pair<Rule>) -> Type {
                 "Natural" => Type::Natural,
                 "Integer" => Type::Integer,
                 "Real" => Type::Real,
-                // "Color" => Type::Color,
-                // "Path" => Type::Path,
+                "Color" => Type::Color,
+                // TODO: paths
+                "Path" => return Err(Diagnostic::new(pair.span(), "the Path type isn't supported yet", None)),
                 "Null" => Type::Null,
-                // "Version" => Type::Version, // semver
+                "Version" => Type::Version,
                 "Any" => Type::Any,
                 "Type" => Type::Type,
-                _ => unreachable!()
+                _ => return Err(malformed(pair))
             }
         },
         Rule::paren_type => {
-            parse_type(pair.into_inner().next().unwrap())
+            parse_type(next(&mut pair.children(), pair)?)?
+        },
+        _ => return Err(malformed(pair))
+    })
+}
+
+fn parse_color(pair: &CstNode) -> Result<Color, Diagnostic> {
+    let inner: Vec<&CstNode> = pair.children().collect();
+    let first = inner.first().ok_or_else(|| malformed(pair))?;
+    match first.as_rule() {
+        Rule::hexcolor => {
+            let hex = &first.as_str()[1..];
+            // #rgb and #rgba are shorthand for #rrggbb and #rrggbbaa
+            let hex: String = if hex.len() <= 4 { hex.chars().flat_map(|c| [c, c]).collect() } else { hex.to_string() };
+            let channel = |i: usize| hex.get(i..i + 2).and_then(|c| u8::from_str_radix(c, 16).ok());
+            match (channel(0), channel(2), channel(4)) {
+                (Some(r), Some(g), Some(b)) => Ok(Color { r, g, b, a: channel(6).unwrap_or(255) }),
+                _ => Err(malformed(first))
+            }
         },
         _ => {
-            unreachable!()
+            let channels = inner.iter()
+                .map(|c| c.as_str().parse::<u8>().map_err(|_| Diagnostic::new(c.span(), "color channel out of range", Some("channels go from 0 to 255"))))
+                .collect::<Result<Vec<u8>, Diagnostic>>()?;
+            if channels.len() < 3 {
+                return Err(malformed(pair));
+            }
+            Ok(Color { r: channels[0], g: channels[1], b: channels[2], a: channels.get(3).copied().unwrap_or(255) })
         }
     }
 }
 
-fn parse_expr(pair: Pair<Rule>) -> Expr {
-    match pair.as_rule() {
+// natural numbers, and hexadecimal, octal and binary ones with a 0x, 0o or 0b prefix
+fn parse_integer(pair: &CstNode) -> Result<i64, Diagnostic> {
+    let inner = next(&mut pair.children(), pair)?;
+    let (digits, radix) = match inner.as_rule() {
+        Rule::natural => (inner.as_str(), 10),
+        Rule::hex => (&inner.as_str()[2..], 16),
+        Rule::octal => (&inner.as_str()[2..], 8),
+        Rule::binary => (&inner.as_str()[2..], 2),
+        _ => return Err(malformed(inner))
+    };
+    i64::from_str_radix(digits, radix).map_err(|_| Diagnostic::new(pair.span(), "number too large", Some(&format!("integers can be at most {}", i64::MAX))))
+}
+
+fn parse_bop(pair: &CstNode) -> Result<Bop, Diagnostic> {
+    Ok(match pair.as_str() {
+        "+" => Bop::Plus,
+        "**" => Bop::Pow,
+        "*" => Bop::Times,
+        "-" => Bop::Minus,
+        "/" => Bop::Div,
+        ">" => Bop::Gt,
+        "<" => Bop::Lt,
+        ">=" => Bop::Gte,
+        "<=" => Bop::Lte,
+        "==" => Bop::Eq,
+        "!=" => Bop::Neq,
+        "&&" => Bop::And,
+        "||" => Bop::Or,
+        "^" => Bop::Xor,
+        "??" => Bop::Coalesce,
+        "in" => Bop::In,
+        // any whitespace is allowed between not and in
+        s if s.starts_with("not") => Bop::NotIn,
+        _ => return Err(malformed(pair))
+    })
+}
+
+fn binop(e1: Expr, bop: Bop, e2: Expr) -> Expr {
+    Expr {
+        t: None,
+        span: Span { start: e1.span.start, end: e2.span.end },
+        expr: Binop(Box::new(e1), bop, Box::new(e2))
+    }
+}
+
+// precedence climbing over the operators and operands that follow lhs. Only operators that bind at least as tightly as min are taken
+fn climb(mut lhs: Expr, rest: &mut std::iter::Peekable<impl Iterator<Item = (Bop, Expr)>>, min: u8) -> Expr {
+    while let Some((bop, _)) = rest.peek().filter(|(bop, _)| bop.precedence() >= min) {
+        let bop = *bop;
+        let (_, mut rhs) = rest.next().unwrap();
+        // ** is right associative, the rest are left associative
+        while let Some((next, _)) = rest.peek() {
+            let tighter = next.precedence() > bop.precedence() || (next.precedence() == bop.precedence() && bop == Bop::Pow);
+            if !tighter {
+                break;
+            }
+            let next = next.precedence();
+            rhs = climb(rhs, rest, next);
+        }
+        lhs = binop(lhs, bop, rhs);
+    }
+    lhs
+}
+
+// unary operators, then a function applied to arguments, each of which can be followed by accesses
+fn parse_operand(part: &[&CstNode], parent: &CstNode) -> Result<Expr, Diagnostic> {
+    let unops = part.iter().take_while(|c| c.as_rule() == Rule::unop).count();
+    let mut args: Vec<Expr> = Vec::new();
+    for node in &part[unops..] {
+        match node.as_rule() {
+            Rule::dot_access | Rule::safe_access => {
+                let bop = if node.as_rule() == Rule::dot_access { Bop::Access } else { Bop::SafeAccess };
+                let key = next(&mut node.children(), node)?;
+                let e1 = args.pop().ok_or_else(|| malformed(node))?;
+                let e2 = Expr { t: Some(Type::Text), span: key.span(), expr: Text(parse_ident(key)) };
+                args.push(Expr { span: Span { start: e1.span.start, end: node.span().end }, ..binop(e1, bop, e2) });
+            },
+            Rule::arr_access => {
+                let e2 = parse_expr(next(&mut node.children(), node)?)?;
+                let e1 = args.pop().ok_or_else(|| malformed(node))?;
+                args.push(Expr { span: Span { start: e1.span.start, end: node.span().end }, ..binop(e1, Bop::Access, e2) });
+            },
+            _ => args.push(parse_expr(node)?)
+        }
+    }
+    let mut args = args.into_iter();
+    let mut e = args.next().ok_or_else(|| malformed(parent))?;
+    for arg in args {
+        e = Expr {
+            t: None,
+            span: Span { start: e.span.start, end: arg.span.end },
+            expr: App(Box::new(e), Box::new(arg))
+        };
+    }
+    for unop in part[..unops].iter().rev() {
+        let op = match unop.as_str() {
+            "-" => Uop::Neg,
+            "!" => Uop::Not,
+            _ => return Err(malformed(unop))
+        };
+        e = Expr {
+            t: None,
+            span: Span { start: unop.span().start, end: e.span.end },
+            expr: Unop(op, Box::new(e))
+        };
+    }
+    Ok(e)
+}
+
+fn parse_expr(pair: &CstNode) -> Result<Expr, Diagnostic> {
+    let span = pair.span();
+    Ok(match pair.as_rule() {
         Rule::let_expr => {
-            let l = pair.into_inner().next().unwrap();
+            let l = next(&mut pair.children(), pair)?;
             let istyped = l.as_rule() == Rule::typed_let;
-            let mut it = l.into_inner();
-            let ident = parse_ident(it.next().unwrap());
-            let t = istyped.then(|| parse_type(it.next().unwrap()));
-            let e1 = it.next().unwrap();
-            let e1 = parse_expr(e1);
-            let e2 = it.next().unwrap();
-            let e2 = parse_expr(e2);
+            let mut it = l.children();
+            let ident = parse_ident(next(&mut it, l)?);
+            let t = if istyped { Some(parse_type(next(&mut it, l)?)?) } else { None };
+            let e1 = parse_expr(next(&mut it, l)?)?;
+            let e2 = parse_expr(next(&mut it, l)?)?;
             Expr {
                 t: None,
+                span,
                 expr: Let(ident, t, Box::new(e1), Box::new(e2))
             }
         }
-        Rule::if_expr => {
-            let mut it = pair.into_inner();
-            let b = it.next().unwrap();
-            let b = parse_expr(b);
-            let e1 = it.next().unwrap();
-            let e1 = parse_expr(e1);
-            let e2 = it.next().unwrap();
-            let e2 = parse_expr(e2);
+        Rule::assert_expr => {
+            let mut it = pair.children();
+            let cond = parse_expr(next(&mut it, pair)?)?;
+            let message = parse_expr(next(&mut it, pair)?)?;
+            let e = parse_expr(next(&mut it, pair)?)?;
             Expr {
                 t: None,
-                expr: If(Box::new(b), Box::new(e1), Box::new(e2))
+                span,
+                expr: Assert(Box::new(cond), Box::new(message), Box::new(e))
             }
         },
-        Rule::function_application => {
-            let mut it = pair.into_inner();
-            let e1 = it.next().unwrap();
-            let e1 = parse_expr(e1);
-            let e2 = it.next().unwrap();
-            let e2 = parse_expr(e2);
+        Rule::if_expr => {
+            let mut it = pair.children();
+            let b = parse_expr(next(&mut it, pair)?)?;
+            let e1 = parse_expr(next(&mut it, pair)?)?;
+            let e2 = parse_expr(next(&mut it, pair)?)?;
             Expr {
                 t: None,
-                expr: App(Box::new(e1), Box::new(e2))
+                span,
+                expr: If(Box::new(b), Box::new(e1), Box::new(e2))
             }
         },
         Rule::binop_expr => {
-            let mut it = pair.into_inner();
-            let e1 = it.next().unwrap();
-            let e1 = parse_expr(e1);
-            let bop = it.next().unwrap();
-            let bop: Bop = match bop.as_str() {
-                "+" => Bop::Plus,
-                "**" => Bop::Pow,
-                "*" => Bop::Times,
-                "-" => Bop::Minus,
-                "/" => Bop::Div,
-                ">" => Bop::Gt,
-                "<" => Bop::Lt,
-                ">=" => Bop::Gte,
-                "<=" => Bop::Lte,
-                "==" => Bop::Eq,
-                "!=" => Bop::Neq,
-                "&&" => Bop::And,
-                "||" => Bop::Or,
-                "^" => Bop::Xor,
-                _ => unreachable!()
-            };
-            let e2 = it.next().unwrap();
-            let e2 = parse_expr(e2);
-            Expr {
-                t: None,
-                expr: Binop(Box::new(e1), bop, Box::new(e2))
+            let children: Vec<&CstNode> = pair.children().collect();
+            let mut operands = Vec::new();
+            let mut ops = Vec::new();
+            for part in children.split(|c| c.as_rule() == Rule::binop) {
+                operands.push(parse_operand(part, pair)?);
             }
-        },
-        Rule::access => {
-            let it = pair.into_inner().next().unwrap();
-            match it.as_rule() {
-                Rule::dot_access => {
-                    let mut it = it.into_inner();
-                    let e1 = it.next().unwrap();
-                    let e1 = parse_expr(e1);
-        
-                    let id = it.next().unwrap();
-                    let id = parse_ident(id);
-                    Expr {
-                        t: None,
-                        expr: Binop(Box::new(e1), Bop::Access, Box::new(Expr{t: None, expr: Ident(id)}))
-                    }
-                },
-                Rule::arr_access => {
-                    let mut it = it.into_inner();
-                    let e1 = it.next().unwrap();
-                    let e1 = parse_expr(e1);
-        
-                    let e2 = it.next().unwrap();
-                    let e2 = parse_expr(e2);
-                    Expr {
-                        t: None,
-                        expr: Binop(Box::new(e1), Bop::Access, Box::new(e2))
-                    }
-                },
-                _ => unreachable!()
+            for bop in children.iter().filter(|c| c.as_rule() == Rule::binop) {
+                ops.push(parse_bop(bop)?);
             }
-            
+            let mut operands = operands.into_iter();
+            let first = operands.next().ok_or_else(|| malformed(pair))?;
+            climb(first, &mut ops.into_iter().zip(operands).peekable(), 0)
         },
-        Rule::unop_expr => {
-            let mut it = pair.into_inner();
-            let unop = it.next().unwrap();
-            let unop: Uop = match unop.as_str() {
-                "-" => Uop::Neg,
-                "!" => Uop::Not,
-                _ => unreachable!()
-            };
-            let e = it.next().unwrap();
-            let e = parse_expr(e);
-            Expr {
-                t: None,
-                expr: Unop(unop, Box::new(e))
-            }
-        },
-        Rule::term => parse_expr(pair),
         Rule::list => {
             Expr {
                 t: None,
-                expr: List(pair.into_inner().map(parse_expr).collect())
+                span,
+                expr: List(pair.children().map(parse_expr).collect::<Result<Vec<Expr>, Diagnostic>>()?)
             }
         },
         Rule::record => {
-            let i = pair.into_inner();
-            let mut hashmap: HashMap<Ident, Expr>= HashMap::new();
+            let i = pair.children();
+            let mut hashmap: RecordMap<Expr> = RecordMap::new();
             for record_pair in i {
-                let mut inner_rules = record_pair.into_inner();
-                let k = parse_ident(inner_rules.next().unwrap());
-                let v = parse_expr(inner_rules.next().unwrap());
+                let mut inner_rules = record_pair.children();
+                let k = parse_key(next(&mut inner_rules, record_pair)?)?;
+                let v = parse_expr(next(&mut inner_rules, record_pair)?)?;
                 hashmap.insert(k, v);
             }
             Expr {
                 t: None,
+                span,
                 expr: Record(hashmap)
             }
         },
         Rule::string => Expr {
             t: Some(Type::Text),
-            expr: Text(pair.into_inner().next().unwrap().as_str().to_string())
+            span,
+            expr: Text(string(pair)?)
+        },
+        Rule::version => Expr {
+            t: Some(Type::Version),
+            span,
+            // the grammar allows some versions that semver doesn't, like leading zeros
+            expr: Version(semver::Version::parse(pair.as_str().trim_start_matches('v'))
+                .map_err(|e| Diagnostic::new(span, &format!("invalid version: {e}"), Some("versions look like 1.2.3, 1.2.3-beta.1 or 1.2.3+build")))?)
         },
-        // Rule::version,
         //TODO: maybe split this parsing so that we can get the type better
         Rule::number => {
-            let inner = pair.into_inner().next().unwrap();
+            let inner = next(&mut pair.children(), pair)?;
             match inner.as_rule() {
                 Rule::float_n => Expr {
-                    t: Some(Type::Number),
-                    expr: Float(inner.as_str().parse().unwrap())
+                    t: Some(Type::Real),
+                    span,
+                    expr: Float(inner.as_str().parse::<f64>().ok().filter(|n| n.is_finite())
+                        .ok_or_else(|| Diagnostic::new(span, "number too large", Some(&format!("real numbers can be at most {:e}", f64::MAX))))?)
                 },
+                // literals are never negative, negation is a unary operator
                 Rule::integer_n => Expr {
-                    t: Some(Type::Integer),
-                    expr: Int(inner.as_str().parse().unwrap())
+                    t: Some(Type::Natural),
+                    span,
+                    expr: Int(parse_integer(inner)?)
                 },
-                _ => unreachable!()
+                _ => return Err(malformed(inner))
             }
         },
         Rule::bool => Expr {
             t: Some(Type::Bool),
-            expr: Boolean(pair.as_str().parse().unwrap())
+            span,
+            expr: Boolean(pair.as_str() == "true")
+        },
+        Rule::color => Expr {
+            t: Some(Type::Color),
+            span,
+            expr: Color(parse_color(pair)?)
         },
-        // Rule::color,
         Rule::null => Expr {
             t: Some(Type::Null),
+            span,
             expr: Null
         },
         Rule::ident => Expr {
             t: None,
+            span,
             expr: Ident(parse_ident(pair))
         },
         Rule::lambda => {
-            let lam = pair.into_inner().next().unwrap();
+            let lam = next(&mut pair.children(), pair)?;
             let istyped = lam.as_rule() == Rule::typed_lambda;
-            let mut it = lam.into_inner();
-            let x = parse_ident(it.next().unwrap());
-            let t = istyped.then(|| parse_type(it.next().unwrap()));
-            let e = parse_expr(it.next().unwrap());
+            let mut it = lam.children();
+            let x = parse_ident(next(&mut it, lam)?);
+            let t = if istyped { Some(parse_type(next(&mut it, lam)?)?) } else { None };
+            let e = parse_expr(next(&mut it, lam)?)?;
             Expr {
                 t: None,
+                span,
                 expr: Lambda(x, t, Box::new(e))
             }
         },
         Rule::paren_expr => {
-            parse_expr(pair.into_inner().next().unwrap())
+            parse_expr(next(&mut pair.children(), pair)?)?
         },
-        _ => {
-            println!("{:#?}", pair); //TODO: remove this
-            unreachable!()
+        _ => return Err(malformed(pair))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the structure of an expression, with every operator application in parentheses
+    fn show(e: &Expr) -> String {
+        match &e.expr {
+            Binop(a, Bop::Access, b) => format!("({}.{})", show(a), show(b)),
+            Binop(a, Bop::SafeAccess, b) => format!("({}?.{})", show(a), show(b)),
+            Binop(a, op, b) => format!("({} {op:?} {})", show(a), show(b)),
+            Unop(op, a) => format!("({op:?} {})", show(a)),
+            App(f, x) => format!("({} {})", show(f), show(x)),
+            Ident(id) => id.clone(),
+            Text(s) => s.clone(),
+            Int(n) => n.to_string(),
+            List(l) => format!("[{}]", l.iter().map(show).collect::<Vec<_>>().join(", ")),
+            Let(id, _, value, body) => format!("(let {id} = {} in {})", show(value), show(body)),
+            Lambda(id, _, body) => format!("(\\{id} -> {})", show(body)),
+            _ => format!("{:?}", e.expr)
         }
     }
-}
\ No newline at end of file
+
+    fn parsed(source: &str) -> String {
+        show(&parse(source).unwrap())
+    }
+
+    #[test]
+    fn accesses_bind_tighter_than_operators() {
+        assert_eq!(parsed("a?.b ?? d"), "((a?.b) Coalesce d)");
+        assert_eq!(parsed("a?.b?.c"), "((a?.b)?.c)");
+        assert_eq!(parsed("a.b + 1"), "((a.b) Plus 1)");
+        assert_eq!(parsed("a?.b ?? \"x\""), "((a?.b) Coalesce x)");
+    }
+
+    #[test]
+    fn operators_chain_by_precedence() {
+        assert_eq!(parsed("1 + 2 + 3"), "((1 Plus 2) Plus 3)");
+        assert_eq!(parsed("1 - 2 * 3"), "(1 Minus (2 Times 3))");
+        assert_eq!(parsed("1 * 2 - 3"), "((1 Times 2) Minus 3)");
+        assert_eq!(parsed("2 ** 3 ** 2"), "(2 Pow (3 Pow 2))");
+        assert_eq!(parsed("a < b && c ?? d"), "(((a Lt b) And c) Coalesce d)");
+        assert_eq!(parsed("a >= 1"), "(a Gte 1)");
+        assert_eq!(parsed("x not in xs || y in ys"), "((x NotIn xs) Or (y In ys))");
+    }
+
+    #[test]
+    fn two_character_comparisons_are_not_split() {
+        assert_eq!(parsed("a >= b"), "(a Gte b)");
+        assert_eq!(parsed("a <= b"), "(a Lte b)");
+        assert_eq!(parsed("a > b"), "(a Gt b)");
+    }
+
+    #[test]
+    fn applications_bind_tighter_than_operators() {
+        assert_eq!(parsed("f x + g y"), "((f x) Plus (g y))");
+        assert_eq!(parsed("-f x"), "(Neg (f x))");
+        assert_eq!(parsed("f x.y z"), "((f (x.y)) z)");
+    }
+
+    #[test]
+    fn brackets_index_only_straight_after_an_expression() {
+        assert_eq!(parsed("xs[0]"), "(xs.0)");
+        assert_eq!(parsed("f [0]"), "(f [0])");
+        assert_eq!(parsed("xs[0][1]"), "((xs.0).1)");
+    }
+
+    #[test]
+    fn a_lets_in_ends_its_value() {
+        assert_eq!(parsed(r"let f = \x -> x in f 2"), r"(let f = (\x -> x) in (f 2))");
+        assert_eq!(parsed(r"let f = \x -> let y = x in y in f 2"), r"(let f = (\x -> (let y = x in y)) in (f 2))");
+        assert_eq!(parsed(r"let f = \x -> (x in [1]) in f 2"), r"(let f = (\x -> (x In [1])) in (f 2))");
+        assert_eq!(parsed(r"let x = 1 in [\y -> y in [1]]"), r"(let x = 1 in [(\y -> (y In [1]))])");
+    }
+
+    #[test]
+    fn unicode_escapes_can_be_surrogate_pairs() {
+        assert_eq!(parsed(r#""\u00e9\ud83d\ude00""#), "é😀");
+        assert_eq!(parse_path(r#"a."\u00e9""#), Ok(vec!["a".to_string(), "é".to_string()]));
+    }
+
+    #[test]
+    fn lone_surrogates_are_errors() {
+        let errors = parse(r#"{ a = "x\ud800", b = "\ude00A" }"#).unwrap_err();
+        let errors: Vec<(usize, &str)> = errors.iter().map(|d| (d.span.unwrap().start, d.message.as_str())).collect();
+        assert_eq!(errors, [(8, "\\ud800 is not a character"), (22, "\\ude00 is not a character")]);
+        assert!(parse_path(r#"a."\ud800""#).unwrap_err().contains("\\ud800 is not a character"));
+    }
+}
@@ -0,0 +1,436 @@
+use crate::ast::{Value, RecordMap, KeyOrder};
+use crate::block::Block;
+use crate::cli::OutputFormat;
+use crate::output::{self, PathSegment, display_path};
+use clap::ValueEnum;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+// The changes to the system that a config asks for. The config evaluates to a record, and its
+// operations field is a list of them, each a record with a single field naming the operation:
+//   { writeFile = { path = "~/.config/app.toml", contents = { ... }, format = "toml", mode = "0600", header = true } }
+//   { managedBlock = { path = "~/.bashrc", name = "aliases", contents = { ... }, format = "bash", comment = "#" } }
+//   { symlink = { path = "~/.vimrc", target = "~/dotfiles/vimrc" } }
+//   { mkdir = { path = "~/.local/bin" } }
+//   { remove = { path = "~/.oldrc" } }
+//   { run = { command = "brew bundle", dir = "~/dotfiles" } }
+//   { setEnv = { name = "EDITOR", value = "vim" } }
+// paths are absolute or start with ~/. Files in formats that have comments start with a comment saying they're
+// managed, unless header is false. A block is named config unless it's given a name, and its markers use the
+// comment of its format, or # if it has none.
+// Operations run in the order they're written in, except that each one runs after the ones it depends on:
+// any operation can be given an id, and the ids of the operations it has to run after, like
+//   { run = { command = "brew bundle", id = "brew", after = ["brewfile"] } }
+// and some dependencies come from the paths: what's in a directory is made after the directory, a link after what
+// it points to, and a command after the environment is set and after anything in or making the directory it runs in
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Operation {
+    // the mode is kept from the file that's already there if it isn't given
+    WriteFile { path: PathBuf, contents: String, mode: Option<u32> },
+    // a block between marker comments in a file that something else owns, which keeps the rest of the file
+    ManagedBlock { path: PathBuf, block: Block, contents: String },
+    Symlink { path: PathBuf, target: PathBuf },
+    Mkdir { path: PathBuf },
+    Remove { path: PathBuf },
+    // runs in sh, in the home directory unless dir is given
+    Run { command: String, dir: Option<PathBuf> },
+    // for the commands that are run. null unsets the variable
+    SetEnv { name: String, value: Option<String> }
+}
+
+impl Operation {
+    // the file or directory the operation changes
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Operation::WriteFile { path, .. }
+            | Operation::ManagedBlock { path, .. }
+            | Operation::Symlink { path, .. }
+            | Operation::Mkdir { path }
+            | Operation::Remove { path } => Some(path),
+            Operation::Run { .. } | Operation::SetEnv { .. } => None
+        }
+    }
+
+    // whether the operation has to run after other, because of the paths they change
+    fn needs(&self, other: &Operation) -> bool {
+        let made = match other {
+            Operation::Remove { .. } => None,
+            other => other.path()
+        };
+        match (self, other) {
+            (Operation::Run { .. }, Operation::SetEnv { .. }) => true,
+            (Operation::Run { dir: Some(dir), .. }, _) => made.is_some_and(|p| dir.starts_with(p) || p.starts_with(dir)),
+            (Operation::Symlink { target, .. }, _) if made.is_some_and(|p| target.starts_with(p)) => true,
+            (_, Operation::Mkdir { path: dir }) => self.path().is_some_and(|p| p != dir && p.starts_with(dir)),
+            _ => false
+        }
+    }
+}
+
+// an operation as it's written, with its id and the ids of the operations it runs after
+struct Entry {
+    operation: Operation,
+    id: Option<String>,
+    after: Vec<String>
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operation::WriteFile { path, contents, mode: Some(mode) } => write!(f, "write {} ({} bytes, mode {mode:04o})", path.display(), contents.len()),
+            Operation::WriteFile { path, contents, mode: None } => write!(f, "write {} ({} bytes)", path.display(), contents.len()),
+            Operation::ManagedBlock { path, block, .. } => write!(f, "edit the {} block in {}", block.name, path.display()),
+            Operation::Symlink { path, target } => write!(f, "link {} -> {}", path.display(), target.display()),
+            Operation::Mkdir { path } => write!(f, "mkdir {}", path.display()),
+            Operation::Remove { path } => write!(f, "remove {}", path.display()),
+            Operation::Run { command, dir: Some(dir) } => write!(f, "run {command} (in {})", dir.display()),
+            Operation::Run { command, dir: None } => write!(f, "run {command}"),
+            Operation::SetEnv { name, value: Some(value) } => write!(f, "set {name}={value}"),
+            Operation::SetEnv { name, value: None } => write!(f, "unset {name}")
+        }
+    }
+}
+
+// the operations of a config, in the order they run
+#[derive(Debug, Clone, Default)]
+pub struct Plan {
+    pub operations: Vec<Operation>
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, op) in self.operations.iter().enumerate() {
+            writeln!(f, "{}. {op}", i + 1)?;
+        }
+        Ok(())
+    }
+}
+
+// the plan for an evaluated config. A config without an operations field doesn't change anything
+pub fn plan(config: &Value) -> Result<Plan, String> {
+    let list = match config {
+        Value::Record(hm) => match hm.get("operations") {
+            Some(Value::List(l)) => l,
+            Some(_) => return Err("operations must be a list".to_string()),
+            None => return Ok(Plan::default())
+        },
+        _ => return Ok(Plan::default())
+    };
+    let mut entries = Vec::new();
+    for (i, v) in list.iter().enumerate() {
+        let path = [PathSegment::Key("operations".to_string()), PathSegment::Index(i)];
+        entries.push(operation(v, &path)?);
+    }
+    let dependencies = dependencies(&entries)?;
+    let operations: Vec<Operation> = entries.into_iter().map(|e| e.operation).collect();
+    let order = check(&operations, &dependencies)?;
+    Ok(Plan { operations: order.into_iter().map(|i| operations[i].clone()).collect() })
+}
+
+// the operations each operation runs after
+fn dependencies(entries: &[Entry]) -> Result<Vec<Vec<usize>>, String> {
+    let mut ids: std::collections::HashMap<&str, usize> = std::collections::HashMap::new();
+    for (i, entry) in entries.iter().enumerate() {
+        if let Some(id) = &entry.id {
+            if let Some(j) = ids.insert(id, i) {
+                return Err(format!("operations[{j}] and operations[{i}] both have the id {id:?}"));
+            }
+        }
+    }
+    entries.iter().enumerate().map(|(i, entry)| {
+        let mut after = Vec::new();
+        for id in &entry.after {
+            match ids.get(id.as_str()) {
+                Some(j) => after.push(*j),
+                None => return Err(format!("operations[{i}] runs after {id:?}, but no operation has that id"))
+            }
+        }
+        after.extend((0..entries.len()).filter(|j| *j != i && entry.operation.needs(&entries[*j].operation)));
+        Ok(after)
+    }).collect()
+}
+
+fn error(path: &[PathSegment], message: &str) -> String {
+    format!("{}: {message}", display_path(path))
+}
+
+fn operation(v: &Value, path: &[PathSegment]) -> Result<Entry, String> {
+    let (kind, fields) = match v {
+        Value::Record(hm) if hm.len() == 1 => hm.iter().next().unwrap(),
+        _ => return Err(error(path, "an operation must be a record with a single field, like { mkdir = { path = \"~/dir\" } }"))
+    };
+    let path = [path, &[PathSegment::Key(kind.clone())]].concat();
+    let fields = match fields {
+        Value::Record(hm) => hm,
+        _ => return Err(error(&path, "must be a record"))
+    };
+    let id = match fields.get("id") {
+        Some(_) => Some(text(fields, "id", &path)?),
+        None => None
+    };
+    let after = match fields.get("after") {
+        Some(Value::List(l)) => l.iter().map(|v| match v {
+            Value::Text(t) => Ok(t.clone()),
+            _ => Err(error(&path, "after must be a list of ids"))
+        }).collect::<Result<Vec<String>, String>>()?,
+        Some(_) => return Err(error(&path, "after must be a list of ids")),
+        None => Vec::new()
+    };
+    let operation = match kind.as_str() {
+        "writeFile" => {
+            let target = file_path(fields, "path", &path)?;
+            let (contents, format) = contents(fields, &target, &path)?;
+            let header = match fields.get("header") {
+                Some(Value::Boolean(b)) => *b,
+                Some(_) => return Err(error(&path, "header must be true or false")),
+                None => true
+            };
+            let contents = match format.and_then(comment).filter(|_| header) {
+                Some(comment) => with_header(&contents, comment),
+                None => contents
+            };
+            let mode = match fields.get("mode") {
+                Some(Value::Text(m)) => Some(u32::from_str_radix(m, 8).ok().filter(|m| *m <= 0o7777)
+                    .ok_or_else(|| error(&path, &format!("mode {m:?} must be octal, like \"0644\"")))?),
+                Some(_) => return Err(error(&path, "mode must be text, like \"0644\"")),
+                None => None
+            };
+            Operation::WriteFile { path: target, contents, mode }
+        },
+        "managedBlock" => {
+            let target = file_path(fields, "path", &path)?;
+            let (contents, format) = contents(fields, &target, &path)?;
+            let name = match fields.get("name") {
+                Some(_) => text(fields, "name", &path)?,
+                None => "config".to_string()
+            };
+            let comment = match fields.get("comment") {
+                Some(_) => text(fields, "comment", &path)?,
+                None => format.and_then(comment).unwrap_or("#").to_string()
+            };
+            if name.contains('\n') || comment.contains('\n') || comment.trim().is_empty() {
+                return Err(error(&path, "the name and comment must be on one line, and the comment can't be empty"));
+            }
+            Operation::ManagedBlock { path: target, block: Block { name, comment }, contents }
+        },
+        "symlink" => Operation::Symlink { path: file_path(fields, "path", &path)?, target: file_path(fields, "target", &path)? },
+        "mkdir" => Operation::Mkdir { path: file_path(fields, "path", &path)? },
+        "remove" => Operation::Remove { path: file_path(fields, "path", &path)? },
+        "run" => {
+            let command = text(fields, "command", &path)?;
+            if command.trim().is_empty() {
+                return Err(error(&path, "command is empty"));
+            }
+            let dir = match fields.get("dir") {
+                Some(_) => Some(file_path(fields, "dir", &path)?),
+                None => None
+            };
+            Operation::Run { command, dir }
+        },
+        "setEnv" => {
+            let name = text(fields, "name", &path)?;
+            if !output::shell::is_var_name(&name) {
+                return Err(error(&path, &format!("{name:?} is not a valid environment variable name")));
+            }
+            let value = match fields.get("value") {
+                Some(Value::Null) | None => None,
+                Some(_) => Some(text(fields, "value", &path)?)
+            };
+            Operation::SetEnv { name, value }
+        },
+        _ => return Err(error(&path, "unknown operation. It must be writeFile, managedBlock, symlink, mkdir, remove, run or setEnv"))
+    };
+    Ok(Entry { operation, id, after })
+}
+
+fn text(fields: &RecordMap<Value>, key: &str, path: &[PathSegment]) -> Result<String, String> {
+    match fields.get(key) {
+        Some(Value::Text(t)) => Ok(t.clone()),
+        Some(_) => Err(error(path, &format!("{key} must be text"))),
+        None => Err(error(path, &format!("{key} is missing")))
+    }
+}
+
+// a leading ~ is the home directory, like in a shell
+pub fn expand_home(p: &str) -> Option<PathBuf> {
+    match p.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+            let home = std::env::var_os("HOME")?;
+            Some(PathBuf::from(format!("{}{rest}", home.to_string_lossy())))
+        },
+        _ => Some(PathBuf::from(p))
+    }
+}
+
+fn file_path(fields: &RecordMap<Value>, key: &str, path: &[PathSegment]) -> Result<PathBuf, String> {
+    let p = text(fields, key, path)?;
+    match expand_home(&p) {
+        Some(expanded) if expanded.is_absolute() => Ok(expanded),
+        Some(_) => Err(error(path, &format!("{key} {p:?} must be absolute or start with ~/"))),
+        None => Err(error(path, &format!("{key} {p:?} starts with ~, but $HOME isn't set")))
+    }
+}
+
+// text is written as it is, and other values in the format given, or the one the file's extension suggests.
+// the format is returned too, if there is one
+fn contents(fields: &RecordMap<Value>, target: &Path, path: &[PathSegment]) -> Result<(String, Option<OutputFormat>), String> {
+    let value = fields.get("contents").ok_or_else(|| error(path, "contents is missing"))?;
+    let format = match fields.get("format") {
+        Some(Value::Text(f)) if f == "text" => None,
+        Some(Value::Text(f)) => Some(OutputFormat::from_str(f, true).map_err(|_| error(path, &format!("{f:?} is not a format")))?),
+        Some(_) => return Err(error(path, "format must be text")),
+        None => {
+            let extension = target.extension().and_then(|e| e.to_str()).unwrap_or_default();
+            match extension {
+                "yml" => Some(OutputFormat::Yaml),
+                "sh" => Some(OutputFormat::Bash),
+                _ => OutputFormat::from_str(extension, true).ok().filter(|f| *f != OutputFormat::Debug)
+            }
+        }
+    };
+    let contents = match (format, value) {
+        (_, Value::Text(t)) => t.clone(),
+        (None, _) => return Err(error(path, "the contents aren't text, so a format is needed")),
+        (Some(format), v) => output::render(v, format, true, KeyOrder::Insertion).map_err(|e| error(path, &e.to_string()))?
+    };
+    Ok((contents, format))
+}
+
+// the first line of every file apply writes that has a header
+pub const HEADER: &str = "Managed by configurator. Changes made here will be lost the next time the config is applied";
+
+// how a line comment starts in a format, if it has them
+fn comment(format: OutputFormat) -> Option<&'static str> {
+    match format {
+        OutputFormat::Toml | OutputFormat::Yaml | OutputFormat::Bash | OutputFormat::Zsh | OutputFormat::Fish => Some("#"),
+        OutputFormat::Ini => Some(";"),
+        OutputFormat::Json | OutputFormat::Debug => None
+    }
+}
+
+// the header goes after a #! line, which has to stay first
+fn with_header(contents: &str, comment: &str) -> String {
+    let header = format!("{comment} {HEADER}\n");
+    if contents.contains(HEADER) {
+        return contents.to_string();
+    }
+    match contents.strip_prefix("#!") {
+        Some(_) => {
+            let (shebang, rest) = contents.split_at(contents.find('\n').map_or(contents.len(), |i| i + 1));
+            let newline = if shebang.ends_with('\n') { "" } else { "\n" };
+            format!("{shebang}{newline}{header}{rest}")
+        },
+        None => format!("{header}{contents}")
+    }
+}
+
+// operations that can't all happen, or else the order they run in
+fn check(operations: &[Operation], dependencies: &[Vec<usize>]) -> Result<Vec<usize>, String> {
+    for (i, a) in operations.iter().enumerate() {
+        for (j, b) in operations.iter().enumerate().skip(i + 1) {
+            let describe = || format!("operations[{i}] ({a}) and operations[{j}] ({b})");
+            match (a, b) {
+                (Operation::SetEnv { name: n1, .. }, Operation::SetEnv { name: n2, .. }) if n1 == n2 => {
+                    return Err(format!("{} both set {n1}", describe()));
+                },
+                _ => ()
+            }
+            let (Some(p1), Some(p2)) = (a.path(), b.path()) else { continue };
+            // a file can have several blocks, as long as they have different names
+            if let (Operation::ManagedBlock { block: b1, .. }, Operation::ManagedBlock { block: b2, .. }) = (a, b) {
+                if b1.name != b2.name {
+                    continue;
+                }
+            }
+            if p1 == p2 {
+                return Err(format!("{} both change {}", describe(), p1.display()));
+            }
+            // anything inside a directory that's removed would be removed with it
+            let removes = |op: &Operation, p: &Path, other: &Path| matches!(op, Operation::Remove { .. }) && other.starts_with(p);
+            if removes(a, p1, p2) || removes(b, p2, p1) {
+                return Err(format!("{} conflict, as one removes the other", describe()));
+            }
+        }
+    }
+    order(dependencies).map_err(|cycle| {
+        let describe = |i: &usize| format!("operations[{i}] ({})", operations[*i]);
+        let steps: Vec<String> = cycle.iter().chain(cycle.first()).map(describe).collect();
+        format!("operations depend on each other: {}", steps.join(" runs after "))
+    })
+}
+
+// the written order, except that each operation comes after the ones it depends on, or else operations that depend on each other
+fn order(dependencies: &[Vec<usize>]) -> Result<Vec<usize>, Vec<usize>> {
+    let mut done = vec![false; dependencies.len()];
+    let mut order = Vec::new();
+    while order.len() < dependencies.len() {
+        match (0..dependencies.len()).find(|i| !done[*i] && dependencies[*i].iter().all(|j| done[*j])) {
+            Some(i) => {
+                done[i] = true;
+                order.push(i);
+            },
+            None => {
+                // every operation that's left waits for another one that's left, so following them goes round in a cycle
+                let mut path = vec![(0..dependencies.len()).find(|i| !done[*i]).unwrap()];
+                loop {
+                    let next = *dependencies[*path.last().unwrap()].iter().find(|j| !done[**j]).unwrap();
+                    if let Some(start) = path.iter().position(|i| *i == next) {
+                        return Err(path.split_off(start));
+                    }
+                    path.push(next);
+                }
+            }
+        }
+    }
+    Ok(order)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plan_of(operations: &str) -> Result<Vec<String>, String> {
+        let e = crate::parser::parse(&format!("{{ operations = {operations} }}")).unwrap();
+        let config = crate::interpreter::normalize(&e, &crate::builtins::values(Default::default()), &mut Vec::new()).unwrap();
+        Ok(plan(&config)?.operations.iter().map(|op| op.to_string()).collect())
+    }
+
+    #[test]
+    fn operations_keep_their_order_unless_they_depend_on_each_other() {
+        let plan = plan_of(r#"[
+            { run = { command = "b" } },
+            { writeFile = { path = "/d/e/f", contents = "" } },
+            { mkdir = { path = "/d/e" } },
+            { run = { command = "a", dir = "/d" } },
+            { mkdir = { path = "/d" } },
+            { setEnv = { name = "X", value = "1" } }
+        ]"#);
+        assert_eq!(plan.unwrap(), ["mkdir /d", "mkdir /d/e", "write /d/e/f (0 bytes)", "set X=1", "run b", "run a (in /d)"]);
+    }
+
+    #[test]
+    fn declared_dependencies_are_followed() {
+        let plan = plan_of(r#"[
+            { run = { command = "b", after = ["a"] } },
+            { run = { command = "a", id = "a" } }
+        ]"#);
+        assert_eq!(plan.unwrap(), ["run a", "run b"]);
+        assert!(plan_of(r#"[{ run = { command = "b", after = ["a"] } }]"#).unwrap_err().contains("no operation has that id"));
+    }
+
+    #[test]
+    fn cycles_are_reported() {
+        let plan = plan_of(r#"[
+            { run = { command = "a", id = "a", after = ["b"] } },
+            { run = { command = "b", id = "b", after = ["a"] } }
+        ]"#);
+        assert_eq!(plan.unwrap_err(), "operations depend on each other: operations[0] (run a) runs after operations[1] (run b) runs after operations[0] (run a)");
+        let plan = plan_of(r#"[
+            { symlink = { path = "/a", target = "/b" } },
+            { mkdir = { path = "/b", after = ["c"] } },
+            { run = { command = "c", id = "c", dir = "/a" } }
+        ]"#);
+        assert!(plan.unwrap_err().starts_with("operations depend on each other"));
+    }
+}
@@ -0,0 +1,664 @@
+use crate::ast::{Expr, ExprKind, Type, Bop, Uop, Value};
+use crate::cst::Token;
+use crate::parser::{GrammarParser, Rule};
+use pest::Parser;
+use crate::output::json::write_string;
+
+// Pretty printing source code, in the style of Wadler's "A prettier printer".
+// A group is laid out on one line if it fits in the width, otherwise its lines are broken.
+// Comments aren't part of the AST, so they are taken from the CST and put back next to the nearest token: on the line of the
+// let, field or list item before them, or else before the expression that follows them.
+// Literals are printed as they were spelled in the source
+
+#[derive(Debug, Clone)]
+pub enum Doc {
+    Text(String),
+    // a space, or a newline if the enclosing group is broken
+    Line,
+    // nothing, or a newline if the enclosing group is broken
+    SoftLine,
+    // always a newline, so the enclosing groups are always broken
+    HardLine,
+    // prints nothing, but the enclosing groups are always broken, e.g. after a line comment
+    BreakParent,
+    Nest(usize, Box<Doc>),
+    Group(Box<Doc>),
+    Concat(Vec<Doc>)
+}
+
+const INDENT: usize = 4;
+
+fn text(s: impl Into<String>) -> Doc {
+    Doc::Text(s.into())
+}
+
+fn nest(doc: Doc) -> Doc {
+    Doc::Nest(INDENT, Box::new(doc))
+}
+
+fn group(doc: Doc) -> Doc {
+    Doc::Group(Box::new(doc))
+}
+
+pub fn render(doc: &Doc, width: usize) -> String {
+    let mut out = String::new();
+    let mut col = 0;
+    // indentation, whether the enclosing group is flat, and the document still to be printed
+    let mut stack: Vec<(usize, bool, &Doc)> = vec![(0, false, doc)];
+    while let Some((indent, flat, doc)) = stack.pop() {
+        match doc {
+            Doc::Text(s) => {
+                out.push_str(s);
+                col += s.chars().count();
+            },
+            Doc::Line if flat => {
+                out.push(' ');
+                col += 1;
+            },
+            Doc::SoftLine if flat => (),
+            Doc::Line | Doc::SoftLine | Doc::HardLine => {
+                // no trailing whitespace on blank lines
+                while out.ends_with(' ') {
+                    out.pop();
+                }
+                out.push('\n');
+                out.push_str(&" ".repeat(indent));
+                col = indent;
+            },
+            Doc::BreakParent => (),
+            Doc::Nest(i, d) => stack.push((indent + i, flat, d)),
+            Doc::Group(d) => {
+                let flat = flat || (!has_hard_line(d) && fits(width as isize - col as isize, d, &stack));
+                stack.push((indent, flat, d));
+            },
+            Doc::Concat(docs) => stack.extend(docs.iter().rev().map(|d| (indent, flat, d)))
+        }
+    }
+    out
+}
+
+// whether the group fits on one line, along with what follows it up to the next line break
+fn fits(mut remaining: isize, group: &Doc, rest: &[(usize, bool, &Doc)]) -> bool {
+    let mut stack: Vec<(bool, &Doc)> = vec![(true, group)];
+    let mut rest = rest.iter().rev();
+    while remaining >= 0 {
+        let (flat, doc) = match stack.pop() {
+            Some(next) => next,
+            None => match rest.next() {
+                Some((_, flat, doc)) => (*flat, *doc),
+                None => return true
+            }
+        };
+        match doc {
+            Doc::Text(s) => remaining -= s.chars().count() as isize,
+            Doc::Line if flat => remaining -= 1,
+            Doc::SoftLine if flat => (),
+            Doc::Line | Doc::SoftLine | Doc::HardLine => return true,
+            Doc::BreakParent => (),
+            Doc::Nest(_, d) | Doc::Group(d) => stack.push((flat, d)),
+            Doc::Concat(docs) => stack.extend(docs.iter().rev().map(|d| (flat, d)))
+        }
+    }
+    false
+}
+
+fn has_hard_line(doc: &Doc) -> bool {
+    match doc {
+        Doc::HardLine | Doc::BreakParent => true,
+        // block comments can span lines
+        Doc::Text(s) => s.contains('\n'),
+        Doc::Nest(_, d) | Doc::Group(d) => has_hard_line(d),
+        Doc::Concat(docs) => docs.iter().any(has_hard_line),
+        _ => false
+    }
+}
+
+// identifiers can be used as record keys and after a dot without quotes. The grammar decides what one is
+pub fn is_ident(s: &str) -> bool {
+    GrammarParser::parse(Rule::ident, s).is_ok_and(|pairs| pairs.as_str() == s)
+}
+
+fn string(s: &str) -> Doc {
+    // the grammar has the same escapes as JSON
+    let mut out = String::new();
+    write_string(&mut out, s);
+    text(out)
+}
+
+fn key(k: &str) -> Doc {
+    text(key_source(k))
+}
+
+// a record key as it is written in source, quoted if it isn't an identifier
+pub fn key_source(k: &str) -> String {
+    if is_ident(k) {
+        k.to_string()
+    } else {
+        let mut out = String::new();
+        write_string(&mut out, k);
+        out
+    }
+}
+
+// the grammar only allows a real literal to be digits with a decimal point
+fn float(n: f64) -> String {
+    if n.is_nan() {
+        "(0.0 / 0.0)".to_string()
+    } else if n.is_infinite() {
+        format!("({}1.0 / 0.0)", if n < 0.0 { "-" } else { "" })
+    } else if n.fract() == 0.0 {
+        format!("{n:.1}")
+    } else {
+        n.to_string()
+    }
+}
+
+// a value as source code on one line, for messages
+pub fn value_source(v: &Value) -> String {
+    match v {
+        Value::Null => "null".to_string(),
+        Value::Boolean(b) => b.to_string(),
+        Value::Int(n) => n.to_string(),
+        Value::Float(n) => float(*n),
+        Value::Text(t) => {
+            let mut out = String::new();
+            write_string(&mut out, t);
+            out
+        },
+        Value::Color(c) => c.to_string(),
+        Value::Version(v) => v.to_string(),
+        Value::List(l) => format!("[{}]", l.iter().map(value_source).collect::<Vec<String>>().join(", ")),
+        Value::Record(hm) if hm.is_empty() => "{}".to_string(),
+        Value::Record(hm) => format!("{{ {} }}", hm.iter().map(|(k, v)| format!("{} = {}", key_source(k), value_source(v))).collect::<Vec<String>>().join(", ")),
+        Value::Lambda(..) | Value::Builtin(_) => "a function".to_string()
+    }
+}
+
+// a type as source code on one line, for messages
+pub fn type_source(t: &Type) -> String {
+    render(&Printer::new("", Vec::new()).ty(t), usize::MAX)
+}
+
+fn bop(bop: Bop) -> &'static str {
+    match bop {
+        Bop::Eq => "==",
+        Bop::Neq => "!=",
+        Bop::Lt => "<",
+        Bop::Gt => ">",
+        Bop::Lte => "<=",
+        Bop::Gte => ">=",
+        Bop::Access => ".",
+        Bop::Plus => "+",
+        Bop::Minus => "-",
+        Bop::Times => "*",
+        Bop::Div => "/",
+        Bop::And => "&&",
+        Bop::Or => "||",
+        Bop::Xor => "^",
+        Bop::Pow => "**",
+        Bop::Coalesce => "??",
+        Bop::SafeAccess => "?.",
+        Bop::In => "in",
+        Bop::NotIn => "not in"
+    }
+}
+
+
+pub struct Printer<'a> {
+    source: &'a str,
+    comments: Vec<Token<'a>>,
+    // the comments before this one have been printed
+    next_comment: usize
+}
+
+// prints an AST that didn't come from a source file, like one made by convert
+pub fn print_expr(e: &Expr, width: usize) -> String {
+    Printer::new("", Vec::new()).print(e, width)
+}
+
+impl<'a> Printer<'a> {
+    // the comments are the comment tokens of the source's CST
+    pub fn new(source: &'a str, comments: Vec<Token<'a>>) -> Printer<'a> {
+        Printer { source, comments, next_comment: 0 }
+    }
+
+    pub fn print(&mut self, e: &Expr, width: usize) -> String {
+        let doc = Doc::Concat(vec![self.statement(e), self.rest()]);
+        let mut out = render(&doc, width);
+        out.truncate(out.trim_end().len());
+        out.push('\n');
+        out
+    }
+
+    // the comments left after the last expression
+    fn rest(&mut self) -> Doc {
+        if self.next_comment == self.comments.len() {
+            return text("");
+        }
+        let comments = self.leading(usize::MAX);
+        Doc::Concat(vec![Doc::HardLine, comments])
+    }
+
+    // comments that start before pos, each on its own line
+    fn leading(&mut self, pos: usize) -> Doc {
+        let mut docs = Vec::new();
+        while let Some(comment) = self.comments.get(self.next_comment) {
+            if comment.span.start >= pos {
+                break;
+            }
+            if !docs.is_empty() && self.blank_line_before(comment.span.start) {
+                docs.push(Doc::HardLine);
+            }
+            docs.push(text(comment.text));
+            docs.push(Doc::HardLine);
+            self.next_comment += 1;
+        }
+        Doc::Concat(docs)
+    }
+
+    // a comment on the same line as the end of an element, with only the given separator in between
+    fn trailing(&mut self, end: usize, separator: &str) -> Doc {
+        let comment = match self.comments.get(self.next_comment) {
+            Some(comment) if comment.span.start >= end => comment,
+            _ => return text("")
+        };
+        let between = &self.source[end..comment.span.start];
+        let same_line = !between.contains('\n')
+            && between.split_whitespace().all(|w| w == separator || w.chars().all(|c| c == ')' || c == ','));
+        if !same_line {
+            return text("");
+        }
+        let doc = text(format!(" {}", comment.text));
+        self.next_comment += 1;
+        if comment.text.starts_with("//") {
+            Doc::Concat(vec![doc, Doc::BreakParent])
+        } else {
+            doc
+        }
+    }
+
+    // comments inside an expression that start before pos, kept on the same line if they're block comments
+    fn inline(&mut self, pos: usize) -> Doc {
+        let mut docs = Vec::new();
+        while let Some(comment) = self.comments.get(self.next_comment).filter(|c| c.span.start < pos) {
+            docs.push(text(comment.text));
+            docs.push(if comment.text.starts_with("//") { Doc::HardLine } else { text(" ") });
+            self.next_comment += 1;
+        }
+        Doc::Concat(docs)
+    }
+
+    // comments straight after the end of an expression, with only whitespace in between, which stay after it
+    fn after(&mut self, mut end: usize) -> Doc {
+        let mut docs = Vec::new();
+        while let Some(comment) = self.comments.get(self.next_comment) {
+            if comment.span.start < end || !self.source[end..comment.span.start].trim().is_empty() {
+                break;
+            }
+            docs.push(text(format!(" {}", comment.text)));
+            if comment.text.starts_with("//") {
+                docs.push(nest(Doc::HardLine));
+            }
+            end = comment.span.end;
+            self.next_comment += 1;
+        }
+        Doc::Concat(docs)
+    }
+
+    // a literal as it was spelled in the source, like 0x1F or v1.2.3, if it came from the source
+    fn literal(&self, e: &Expr) -> Option<Doc> {
+        let source = self.source.get(e.span.start..e.span.end).filter(|s| !s.is_empty())?;
+        // a key after a dot is text spelled as an identifier
+        let quoted = source.starts_with('"');
+        (quoted == matches!(e.expr, ExprKind::Text(_))).then(|| text(source))
+    }
+
+    // a blank line in the source before pos is kept
+    fn blank_line_before(&self, pos: usize) -> bool {
+        let before = &self.source[..pos.min(self.source.len())];
+        before.chars().rev().take_while(|c| c.is_whitespace()).filter(|c| *c == '\n').count() >= 2
+    }
+
+    // comments after the last element of a record or list, each on its own line
+    fn dangling(&mut self, end: usize) -> Doc {
+        let mut docs = Vec::new();
+        while let Some(comment) = self.comments.get(self.next_comment).filter(|c| c.span.start < end) {
+            docs.push(Doc::HardLine);
+            docs.push(text(comment.text));
+            self.next_comment += 1;
+        }
+        Doc::Concat(docs)
+    }
+
+    // an empty record or list, which can still have comments inside
+    fn empty(&mut self, open: &str, close: &str, end: usize) -> Doc {
+        let comments = self.dangling(end);
+        if has_hard_line(&comments) {
+            Doc::Concat(vec![text(open), nest(comments), Doc::HardLine, text(close)])
+        } else {
+            text(format!("{open}{close}"))
+        }
+    }
+
+    // the start of an element, with its leading comments and a blank line if there was one
+    fn element_start(&mut self, start: usize, first: bool) -> Doc {
+        let blank = !first && self.blank_line_before(self.comments.get(self.next_comment)
+            .filter(|c| c.span.start < start)
+            .map_or(start, |c| c.span.start));
+        let comments = self.leading(start);
+        Doc::Concat(vec![if blank { Doc::HardLine } else { text("") }, comments])
+    }
+
+    // an expression on its own lines, such as the body of a let
+    fn statement(&mut self, e: &Expr) -> Doc {
+        let start = self.leading(e.span.start);
+        Doc::Concat(vec![start, self.expr(e)])
+    }
+
+    // the rest of the file after a let, assert or type declaration
+    fn body(&mut self, e: &Expr) -> Doc {
+        let start = self.element_start(e.span.start, false);
+        Doc::Concat(vec![start, self.expr(e)])
+    }
+
+    // an expression, after any comments that are still to be printed before it
+    pub fn expr(&mut self, e: &Expr) -> Doc {
+        let comments = self.inline(e.span.start);
+        Doc::Concat(vec![comments, self.expr_only(e)])
+    }
+
+    fn expr_only(&mut self, e: &Expr) -> Doc {
+        use ExprKind::*;
+        match &e.expr {
+            TypeDecl(name, t, body) => {
+                let t = self.ty(t);
+                Doc::Concat(vec![text(format!("type {name} = ")), t, Doc::HardLine, Doc::HardLine, self.statement(body)])
+            },
+            Let(id, t, value, body) => {
+                let annotation = match t {
+                    Some(t) => Doc::Concat(vec![text(" : "), self.ty(t)]),
+                    None => text("")
+                };
+                let value_doc = self.value(value);
+                let comment = self.trailing(value.span.end, "in");
+                Doc::Concat(vec![text(format!("let {id}")), annotation, text(" = "), value_doc, text(" in"), comment, Doc::HardLine, self.body(body)])
+            },
+            Assert(cond, message, body) => {
+                let cond = self.operand(cond, 0, true);
+                let message_doc = self.value(message);
+                let comment = self.trailing(message.span.end, "in");
+                Doc::Concat(vec![text("assert "), cond, text(" : "), message_doc, text(" in"), comment, Doc::HardLine, self.body(body)])
+            },
+            If(cond, e1, e2) => {
+                let cond = self.term(cond);
+                let e1 = self.term(e1);
+                let e2 = self.term(e2);
+                group(Doc::Concat(vec![
+                    text("if "),
+                    cond,
+                    nest(Doc::Concat(vec![Doc::Line, text("then "), e1, Doc::Line, text("else "), e2]))
+                ]))
+            },
+            App(f, x) => {
+                let f_doc = if matches!(f.expr, App(..)) { self.expr(f) } else { self.atom(f) };
+                let comments = self.after(f.span.end);
+                let space = if has_hard_line(&comments) { "" } else { " " };
+                Doc::Concat(vec![f_doc, comments, text(space), self.atom(x)])
+            },
+            Binop(e1, Bop::Access | Bop::SafeAccess, e2) => {
+                let op = if matches!(&e.expr, Binop(_, Bop::SafeAccess, _)) { "?." } else { "." };
+                match &e2.expr {
+                    Text(k) if is_ident(k) => Doc::Concat(vec![self.atom(e1), text(op), text(k)]),
+                    _ => Doc::Concat(vec![self.atom(e1), text("["), self.expr(e2), text("]")])
+                }
+            },
+            Binop(e1, op, e2) => {
+                // ** groups to the right and the rest to the left, so an operand on that side with the same precedence doesn't need parentheses
+                let right = *op == Bop::Pow;
+                let e1_doc = self.operand(e1, op.precedence(), !right);
+                let comments = self.after(e1.span.end);
+                let space = if has_hard_line(&comments) { "" } else { " " };
+                let e2_doc = self.operand(e2, op.precedence(), right);
+                Doc::Concat(vec![e1_doc, comments, text(format!("{space}{} ", bop(*op))), e2_doc])
+            },
+            Unop(op, e) => {
+                let e_doc = self.operand(e, u8::MAX, false);
+                Doc::Concat(vec![text(match op { Uop::Neg => "-", Uop::Not => "!" }), e_doc])
+            },
+            Lambda(id, None, body) => Doc::Concat(vec![text(format!("\\{id} -> ")), self.expr(body)]),
+            Lambda(id, Some(t), body) => {
+                let t = self.ty(t);
+                Doc::Concat(vec![text(format!("\\({id} : ")), t, text(") -> "), self.expr(body)])
+            },
+            Record(hm) if hm.is_empty() => self.empty("{", "}", e.span.end),
+            Record(hm) => {
+                let mut items = Vec::new();
+                for (i, (k, v)) in hm.iter().enumerate() {
+                    let start = self.element_start(v.span.start, i == 0);
+                    let v_doc = self.expr(v);
+                    let comment = self.trailing(v.span.end, "");
+                    items.push((Doc::Concat(vec![start, key(k), text(" = "), v_doc]), comment));
+                }
+                let dangling = self.dangling(e.span.end);
+                collection("{", items, dangling, "}", Doc::Line)
+            },
+            List(l) if l.is_empty() => self.empty("[", "]", e.span.end),
+            List(l) => {
+                let mut items = Vec::new();
+                for (i, v) in l.iter().enumerate() {
+                    let start = self.element_start(v.span.start, i == 0);
+                    let v_doc = self.expr(v);
+                    let comment = self.trailing(v.span.end, "");
+                    items.push((Doc::Concat(vec![start, v_doc]), comment));
+                }
+                let dangling = self.dangling(e.span.end);
+                collection("[", items, dangling, "]", Doc::SoftLine)
+            },
+            Ident(id) => text(id),
+            Text(s) => self.literal(e).unwrap_or_else(|| string(s)),
+            Int(n) => self.literal(e).unwrap_or_else(|| text(n.to_string())),
+            Float(n) => self.literal(e).unwrap_or_else(|| text(float(*n))),
+            Boolean(b) => text(b.to_string()),
+            Null => text("null"),
+            Color(c) => self.literal(e).unwrap_or_else(|| text(c.to_string())),
+            Version(v) => self.literal(e).unwrap_or_else(|| text(v.to_string()))
+        }
+    }
+
+    // an expression where the grammar expects a term, in parentheses if it isn't one
+    fn term(&mut self, e: &Expr) -> Doc {
+        self.parenthesize(e, is_term(e))
+    }
+
+    // a let's value or an assertion's message, which ends at the `in`. A lambda doesn't need parentheses there
+    // unless its body has a membership test that would take the `in`
+    fn value(&mut self, e: &Expr) -> Doc {
+        match &e.expr {
+            ExprKind::Lambda(..) if !has_membership(e) => self.expr(e),
+            _ => self.term(e)
+        }
+    }
+
+    // a function, argument or accessed expression, which can be a term or an access
+    fn atom(&mut self, e: &Expr) -> Doc {
+        let access = matches!(e.expr, ExprKind::Binop(_, Bop::Access | Bop::SafeAccess, _));
+        self.parenthesize(e, access || is_term(e))
+    }
+
+    // an operand of an operator with the given precedence. Operators that bind tighter don't need parentheses,
+    // and nor do ones that bind the same if they're on the side the operator groups to
+    fn operand(&mut self, e: &Expr, precedence: u8, same: bool) -> Doc {
+        use ExprKind::*;
+        let bare = match &e.expr {
+            App(..) | Unop(..) | Binop(_, Bop::Access | Bop::SafeAccess, _) => true,
+            Int(_) => true,
+            Binop(_, op, _) => op.precedence() > precedence || (same && op.precedence() == precedence),
+            _ => is_term(e)
+        };
+        self.parenthesize(e, bare)
+    }
+
+    fn parenthesize(&mut self, e: &Expr, bare: bool) -> Doc {
+        if bare {
+            self.expr(e)
+        } else {
+            Doc::Concat(vec![text("("), self.expr(e), text(")")])
+        }
+    }
+
+    pub fn ty(&mut self, t: &Type) -> Doc {
+        match t {
+            Type::Null => text("Null"),
+            Type::Natural => text("Natural"),
+            Type::Integer => text("Integer"),
+            Type::Real => text("Real"),
+            Type::Number => text("Number"),
+            Type::Bool => text("Bool"),
+            Type::Text => text("Text"),
+            Type::Color => text("Color"),
+            Type::Version => text("Version"),
+            Type::Any => text("Any"),
+            Type::Type => text("Type"),
+            Type::Ident(name) => text(name),
+            Type::List(t) => Doc::Concat(vec![text("["), self.ty(t), text("]")]),
+            // refinements can only be written on record fields
+            Type::Refined(t, _) => self.ty(t),
+            Type::Alternative(a, b) if is_optional(a, b) => Doc::Concat(vec![self.type_term(a), text("?")]),
+            Type::Alternative(a, b) => Doc::Concat(vec![self.type_unit(a), text(" | "), self.ty(b)]),
+            Type::Function(a, b) => Doc::Concat(vec![self.type_unit(a), text(" -> "), self.ty(b)]),
+            Type::Record(hm) if hm.is_empty() => text("{}"),
+            Type::Record(hm) => {
+                let mut items = Vec::new();
+                for (k, t) in hm {
+                    let item = match t {
+                        Type::Refined(base, refinement) => Doc::Concat(vec![
+                            key(k), text(" : "), self.ty(base), text(" where "), self.expr(&refinement.pred)
+                        ]),
+                        t => Doc::Concat(vec![key(k), text(" : "), self.ty(t)])
+                    };
+                    items.push((item, text("")));
+                }
+                collection("{", items, text(""), "}", Doc::Line)
+            }
+        }
+    }
+
+    // a type where the grammar expects a type_term, in parentheses if it isn't one
+    fn type_term(&mut self, t: &Type) -> Doc {
+        match t {
+            Type::Alternative(..) | Type::Function(..) => Doc::Concat(vec![text("("), self.ty(t), text(")")]),
+            Type::Refined(t, _) => self.type_term(t),
+            t => self.ty(t)
+        }
+    }
+
+    // a type where the grammar expects a type_unit, which can also be an optional type
+    fn type_unit(&mut self, t: &Type) -> Doc {
+        match t {
+            Type::Alternative(a, b) if is_optional(a, b) => self.ty(t),
+            Type::Refined(t, _) => self.type_unit(t),
+            t => self.type_term(t)
+        }
+    }
+}
+
+fn is_term(e: &Expr) -> bool {
+    use ExprKind::*;
+    match &e.expr {
+        Let(..) | TypeDecl(..) | Assert(..) | If(..) | App(..) | Binop(..) | Unop(..) | Lambda(..) => false,
+        // negative numbers are printed with a unary minus
+        Int(n) => *n >= 0,
+        Float(n) => n.is_sign_positive() && n.is_finite(),
+        _ => true
+    }
+}
+
+// whether an operator chain, or the body of a lambda, has an `in` or `not in` that could be printed without parentheses
+fn has_membership(e: &Expr) -> bool {
+    match &e.expr {
+        ExprKind::Binop(a, op, b) => matches!(op, Bop::In | Bop::NotIn) || has_membership(a) || has_membership(b),
+        ExprKind::Lambda(_, _, body) => has_membership(body),
+        _ => false
+    }
+}
+
+// T | Null is written as T?
+fn is_optional(a: &Type, b: &Type) -> bool {
+    matches!(b, Type::Null) && !matches!(a, Type::Null)
+}
+
+// items separated by commas, either all on one line or each on its own line.
+// each item comes with the comment that was on the same line, which goes after its comma
+fn collection(open: &str, items: Vec<(Doc, Doc)>, dangling: Doc, close: &str, padding: Doc) -> Doc {
+    let mut inner = vec![padding.clone()];
+    let count = items.len();
+    for (i, (item, comment)) in items.into_iter().enumerate() {
+        inner.push(item);
+        if i + 1 < count {
+            inner.push(text(","));
+            inner.push(comment);
+            inner.push(Doc::Line);
+        } else {
+            inner.push(comment);
+        }
+    }
+    inner.push(dangling);
+    group(Doc::Concat(vec![text(open), nest(Doc::Concat(inner)), padding, text(close)]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(source: &str) -> String {
+        let e = crate::parser::parse(source).unwrap();
+        let tree = crate::cst::parse(source).unwrap();
+        Printer::new(source, tree.comments()).print(&e, 80)
+    }
+
+    #[test]
+    fn literals_keep_their_spelling() {
+        let source = "{ a = 0x1F, b = v1.2.3, c = 0o17, d = 0b101, e = 1.50, f = \"\\u0041\", g = #FFF }\n";
+        assert_eq!(format(source), source);
+    }
+
+    #[test]
+    fn comments_stay_where_they_were() {
+        assert_eq!(format("let x = /* inline */ 1 in // trailing\nx\n"), "let x = /* inline */ 1 in // trailing\nx\n");
+        assert_eq!(format("{ a = x /* after x */ + y }\n"), "{ a = x /* after x */ + y }\n");
+        assert_eq!(format("[1, /* two */ 2]\n"), "[1, /* two */ 2]\n");
+        assert_eq!(format("f // why\n    x\n"), "f // why\n    x\n");
+    }
+
+    #[test]
+    fn formatting_is_idempotent() {
+        let sources = [
+            "let square = \\(y) -> y * y in\nlet x : Number = 12 in {\n    hello = square(x), // the square\n\n    eleven = 4 + 2.12, another = (if (2 == 2) then \"foo\" else \"bar\")\n}",
+            "// leading\nlet a = { b = null } in\n/* before */ { c = a?.b ?? \"d\", e = (1 + 2) * 3, f = 2 ** (3 ** 2), g = [] // empty\n}",
+            "assert 1 < 2 && true : \"ordered\" in { xs = [1, 2, 3], y = xs[0] + -1, z = -(1 + 2) }"
+        ];
+        for source in sources {
+            let once = format(source);
+            assert_eq!(format(&once), once, "formatting {source:?}");
+        }
+    }
+
+    #[test]
+    fn operands_are_only_parenthesized_when_needed() {
+        assert_eq!(format("(1 + 2) + (3 * 4)"), "1 + 2 + 3 * 4\n");
+        assert_eq!(format("1 - (2 - 3)"), "1 - (2 - 3)\n");
+        assert_eq!(format("(a.b).c"), "a.b.c\n");
+        assert_eq!(format("let f = (\\x -> x) in f"), "let f = \\x -> x in\nf\n");
+        assert_eq!(format("let f = (\\x -> x in [1]) in f"), "let f = (\\x -> x in [1]) in\nf\n");
+    }
+
+    #[test]
+    fn identifiers_follow_the_grammar() {
+        assert!(is_ident("notes"));
+        assert!(is_ident("in_x"));
+        assert!(!is_ident("in"));
+        assert!(!is_ident("where"));
+        assert!(!is_ident("a-b"));
+        assert!(!is_ident(""));
+    }
+}
@@ -0,0 +1,349 @@
+use crate::ast::{Diagnostic, Expr, Span};
+use crate::cst;
+use crate::parser::{self, Rule};
+
+// Parsing that keeps going after a syntax error, so that every error in a file can be reported at once.
+// pest stops at the first error, so after each one the broken part of the source is blanked out and it's
+// parsed again. The broken part is the record field or list element the error is in, or the let the error
+// is in the value of. Blanking keeps the length of the source the same, so the spans of later errors still
+// point into the original source
+
+// the AST of the source, or every error in it
+pub fn parse(source: &str) -> Result<Expr, Vec<Diagnostic>> {
+    let mut diagnostics = Vec::new();
+    scan(source, &mut diagnostics);
+    // the rest of the file can't be made sense of if the brackets don't match up
+    if !diagnostics.is_empty() {
+        return Err(diagnostics);
+    }
+    let mut patched = source.to_string();
+    loop {
+        match cst::parse(&patched) {
+            Ok(tree) => {
+                debug_assert_eq!(tree.to_source(), patched);
+                match parser::lower(&tree) {
+                    Ok(e) if diagnostics.is_empty() => return Ok(e),
+                    Ok(_) => break,
+                    Err(d) => {
+                        // literals that don't make sense, like numbers that are too large, are replaced with ones that do
+                        let found = literal(&tree, d.span.unwrap_or_default());
+                        diagnostics.push(d);
+                        let Some((span, replacement)) = found.filter(|(span, r)| patched[span.start..span.end].trim() != *r) else { break };
+                        let blank = format!("{replacement:<width$}", width = span.end - span.start);
+                        patched.replace_range(span.start..span.end, &blank);
+                    }
+                }
+            },
+            Err(e) => {
+                let pos = match e.location {
+                    pest::error::InputLocation::Pos(p) => p,
+                    pest::error::InputLocation::Span((p, _)) => p
+                };
+                diagnostics.push(syntax_error(&patched, &e.variant, pos));
+                if !recover(&mut patched, pos) {
+                    break;
+                }
+            }
+        }
+    }
+    // errors in literals are only found once the rest of the file parses
+    diagnostics.sort_by_key(|d| d.span.map(|s| s.start));
+    Err(diagnostics)
+}
+
+// the innermost literal an error is in, and what to replace it with
+fn literal(node: &cst::CstNode, span: Span) -> Option<(Span, &'static str)> {
+    if span.start < node.span().start || node.span().end < span.end {
+        return None;
+    }
+    let replacement = match node.as_rule() {
+        Rule::number | Rule::color | Rule::version => Some("0"),
+        Rule::string => Some("\"\""),
+        // types that aren't supported yet
+        Rule::builtin_type => Some("Any"),
+        _ => None
+    };
+    node.children().find_map(|child| literal(child, span)).or(replacement.map(|r| (node.span(), r)))
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Item<'a> {
+    Open(char),
+    Close(char),
+    Comma,
+    Word(&'a str),
+    Punct(&'a str)
+}
+
+// the brackets, commas, words and operators in the source, skipping strings and comments.
+// brackets that don't match are reported
+fn scan<'a>(source: &'a str, diagnostics: &mut Vec<Diagnostic>) -> Vec<(usize, Item<'a>)> {
+    let mut items = Vec::new();
+    let mut open: Vec<(usize, char)> = Vec::new();
+    let mut pos = 0;
+    while pos < source.len() {
+        let rest = &source[pos..];
+        let c = rest.chars().next().unwrap();
+        let len = if rest.starts_with("//") {
+            rest.find('\n').unwrap_or(rest.len())
+        } else if let Some(body) = rest.strip_prefix("/*") {
+            match body.find("*/") {
+                Some(n) => n + 4,
+                None => {
+                    diagnostics.push(error(pos, 2, "unterminated comment", "expected `*/` to end it"));
+                    return items;
+                }
+            }
+        } else if c == '"' {
+            match string_length(rest) {
+                Some(n) => n,
+                None => {
+                    diagnostics.push(error(pos, 1, "unterminated string", "expected a closing `\"`"));
+                    return items;
+                }
+            }
+        } else if "{[(".contains(c) {
+            open.push((pos, c));
+            items.push((pos, Item::Open(c)));
+            1
+        } else if "}])".contains(c) {
+            let opener = match c { '}' => '{', ']' => '[', _ => '(' };
+            if open.iter().any(|(_, o)| *o == opener) {
+                // anything opened since the matching bracket was never closed
+                while let Some((p, o)) = open.pop() {
+                    if o == opener {
+                        break;
+                    }
+                    diagnostics.push(unclosed(p, o));
+                }
+                items.push((pos, Item::Close(c)));
+            } else {
+                diagnostics.push(error(pos, 1, &format!("unexpected `{c}`"), &format!("there is no `{opener}` for it to close")));
+            }
+            1
+        } else if c == ',' {
+            items.push((pos, Item::Comma));
+            1
+        } else if c.is_whitespace() {
+            c.len_utf8()
+        } else {
+            let len = token_length(rest);
+            let text = &rest[..len];
+            items.push((pos, if c.is_alphanumeric() || c == '_' { Item::Word(text) } else { Item::Punct(text) }));
+            len
+        };
+        pos += len;
+    }
+    diagnostics.extend(open.into_iter().map(|(p, o)| unclosed(p, o)));
+    diagnostics.sort_by_key(|d| d.span.map(|s| s.start));
+    items
+}
+
+fn string_length(s: &str) -> Option<usize> {
+    let mut escaped = false;
+    for (i, c) in s.char_indices().skip(1) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => return Some(i + 1),
+            _ => ()
+        }
+    }
+    None
+}
+
+// a word, or a run of punctuation
+fn token_length(s: &str) -> usize {
+    let word = s.starts_with(|c: char| c.is_alphanumeric() || c == '_');
+    s.char_indices()
+        .find(|(_, c)| c.is_whitespace() || "{}[](),\"".contains(*c) || (c.is_alphanumeric() || *c == '_') != word)
+        .map_or(s.len(), |(i, _)| i)
+}
+
+fn error(pos: usize, len: usize, message: &str, hint: &str) -> Diagnostic {
+    Diagnostic::new(Span { start: pos, end: pos + len }, message, Some(hint))
+}
+
+fn unclosed(pos: usize, c: char) -> Diagnostic {
+    let closer = match c { '{' => '}', '[' => ']', _ => ')' };
+    error(pos, 1, &format!("unclosed `{c}`"), &format!("expected a matching `{closer}`"))
+}
+
+fn syntax_error(source: &str, variant: &pest::error::ErrorVariant<Rule>, pos: usize) -> Diagnostic {
+    let rest = source[pos..].trim_end();
+    let (message, len) = match rest.chars().next() {
+        None => ("unexpected end of file".to_string(), 0),
+        Some(c) => {
+            let len = if "{}[](),\"".contains(c) { 1 } else { token_length(rest) };
+            (format!("unexpected `{}`", &rest[..len]), len)
+        }
+    };
+    let hint = match variant {
+        pest::error::ErrorVariant::ParsingError { positives, .. } if !positives.is_empty() => Some(format!("expected {}", expected(positives))),
+        _ => None
+    };
+    Diagnostic { span: Some(Span { start: pos, end: pos + len }), message, hint }
+}
+
+// what the parser was looking for, in words rather than rule names
+fn expected(rules: &[Rule]) -> String {
+    let mut descriptions: Vec<&str> = Vec::new();
+    for rule in rules {
+        let description = match rule {
+            Rule::EOI => "the end of the file",
+            Rule::binop | Rule::not_in | Rule::in_op => "an operator",
+            Rule::record_pair => "a field, like `name = value`",
+            Rule::record_type_pair => "a field type, like `name : Type`",
+            Rule::refinement => "`where`",
+            Rule::type_decl => "a type declaration",
+            Rule::list_type | Rule::record_type | Rule::function_type | Rule::alternative_type
+                | Rule::optional_type | Rule::builtin_type | Rule::user_type | Rule::paren_type => "a type",
+            Rule::ident if rules.len() == 1 => "a name",
+            _ => "a value"
+        };
+        if !descriptions.contains(&description) {
+            descriptions.push(description);
+        }
+    }
+    match descriptions.split_last() {
+        Some((last, [])) => last.to_string(),
+        Some((last, rest)) => format!("{} or {last}", rest.join(", ")),
+        None => String::new()
+    }
+}
+
+// blanks out the part of the source around an error, or returns false if there's nothing left to blank out
+fn recover(source: &mut String, pos: usize) -> bool {
+    let items = scan(source, &mut Vec::new());
+    // the brackets and lets that are still open at the error
+    let mut open: Vec<usize> = Vec::new();
+    let mut lets: Vec<(usize, usize)> = Vec::new();
+    for (i, (p, item)) in items.iter().enumerate() {
+        if *p >= pos {
+            break;
+        }
+        match item {
+            Item::Open(_) => open.push(i),
+            Item::Close(_) => {
+                open.pop();
+                lets.retain(|(_, depth)| *depth <= open.len());
+            },
+            Item::Word("let") => lets.push((i, open.len())),
+            Item::Word("in") => {
+                if let Some(l) = lets.iter().rposition(|(_, depth)| *depth == open.len()) {
+                    lets.remove(l);
+                }
+            },
+            _ => ()
+        }
+    }
+    let bracket = open.iter().rev().find(|i| matches!(items[**i].1, Item::Open('{') | Item::Open('[')));
+    let innermost_let = lets.last().filter(|(l, _)| bracket.is_none_or(|b| l > b));
+    let (start, end, replacement) = match (innermost_let, bracket) {
+        (Some(&(l, depth)), _) => match let_value(&items, l, depth, pos) {
+            Some(region) => region,
+            None => return false
+        },
+        (None, Some(&b)) => match element(&items, b, pos) {
+            Some(region) => region,
+            None => return false
+        },
+        (None, None) => return false
+    };
+    let region = &source[start..end];
+    if region.trim().is_empty() || region.trim() == replacement.trim() {
+        return false;
+    }
+    let blank = format!("{replacement:<width$}", width = region.len());
+    source.replace_range(start..end, &blank);
+    true
+}
+
+// the value of the let that starts at item l, which gets replaced by null, or 0 if null doesn't fit.
+// if neither fits, the whole let is removed, which leaves its body
+fn let_value(items: &[(usize, Item)], l: usize, depth: usize, pos: usize) -> Option<(usize, usize, &'static str)> {
+    let mut d = depth;
+    let mut equals = None;
+    let mut nested = 0;
+    for (p, item) in &items[l + 1..] {
+        match item {
+            Item::Open(_) => d += 1,
+            Item::Close(_) if d == depth => return None,
+            Item::Close(_) => d -= 1,
+            Item::Punct("=") if d == depth && equals.is_none() => equals = Some(*p + 1),
+            Item::Word("let") if d == depth => nested += 1,
+            Item::Word("in") if d == depth && nested > 0 => nested -= 1,
+            Item::Word("in") if d == depth => {
+                // the error has to be in the value for blanking it to help
+                let start = equals.filter(|e| *e <= pos && pos <= *p)?;
+                return Some(match p - start {
+                    n if n >= 6 => (start, *p, " null "),
+                    n if n >= 3 => (start, *p, " 0 "),
+                    _ => (items[l].0, p + "in".len(), "")
+                });
+            },
+            _ => ()
+        }
+    }
+    None
+}
+
+// the record field or list element in the bracket at item b, along with a comma next to it
+fn element(items: &[(usize, Item)], b: usize, pos: usize) -> Option<(usize, usize, &'static str)> {
+    let mut depth = 0;
+    let mut start = (items[b].0, false);
+    for (p, item) in &items[b + 1..] {
+        match item {
+            Item::Open(_) => depth += 1,
+            Item::Close(_) if depth > 0 => depth -= 1,
+            Item::Comma if depth == 0 && *p < pos => start = (*p, true),
+            Item::Comma if depth == 0 => {
+                // the element and the comma after it
+                return Some((start.0 + 1, p + 1, ""));
+            },
+            Item::Close(_) => {
+                // the last element and the comma before it
+                let (s, comma) = start;
+                return Some((if comma { s } else { s + 1 }, *p, ""));
+            },
+            _ => ()
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn errors(source: &str) -> Vec<(usize, String)> {
+        parse(source).unwrap_err().into_iter().map(|d| (d.span.unwrap().start, d.message)).collect()
+    }
+
+    #[test]
+    fn every_let_with_a_missing_value_is_reported() {
+        assert_eq!(errors("let x = in let y = in 3"), [(8, "unexpected `in`".to_string()), (19, "unexpected `in`".to_string())]);
+        assert_eq!(errors("let x =  in let y =      in 3").len(), 2);
+    }
+
+    #[test]
+    fn errors_after_a_broken_element_are_reported() {
+        let errors = errors("{ a = [1, +], b = 99999999999999999999 }");
+        assert_eq!(errors.iter().map(|(pos, _)| *pos).collect::<Vec<usize>>(), [10, 18]);
+    }
+
+    #[test]
+    fn mismatched_brackets_are_reported_alone() {
+        assert_eq!(errors("{ a = [1, 2 }"), [(6, "unclosed `[`".to_string())]);
+    }
+
+    #[test]
+    fn every_bad_literal_is_reported() {
+        let errors = errors(r#"let x : Path = { "\ud800" = "\udc00" } in [rgb(300, 0, 0), 99999999999999999999]"#);
+        let messages: Vec<&str> = errors.iter().map(|(_, m)| m.as_str()).collect();
+        assert_eq!(messages, [
+            "the Path type isn't supported yet", "\\ud800 is not a character", "\\udc00 is not a character",
+            "color channel out of range", "number too large"
+        ]);
+    }
+}
@@ -0,0 +1,11 @@
+// temporary directories for tests
+use std::path::PathBuf;
+
+// an empty directory for a test, removed first if an earlier run left it behind. The prefix, usually the module's
+// name, keeps tests in different modules with the same name apart
+pub fn dir(prefix: &str, name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("configurator-{prefix}-{}-{name}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
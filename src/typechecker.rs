@@ -1,135 +1,240 @@
-use crate::ast::{Expr, TypedExpr, ExprKind, Type, Bop, JoinSemiLattice, Ident};
+use crate::builtins::Builtin;
+use crate::printer;
+use crate::ast::{Diagnostic, Value, RecordMap, Expr, TypedExpr, ExprKind, Type, Bop, Uop, JoinSemiLattice, Ident, Refinement};
 use std::collections::HashMap;
 
-// TODO: account for type aliases
+// type aliases must be resolved before typechecking
 
-pub fn typecheck(expr: &Expr, bindings: &HashMap<Ident, Type>) -> Result<TypedExpr, String> {
+// errors are located at the innermost expression they come from
+pub fn typecheck(expr: &Expr, bindings: &HashMap<Ident, Type>) -> Result<TypedExpr, Diagnostic> {
+    check(expr, bindings).map_err(|e| e.or_at(expr.span))
+}
+
+// every type error, rather than just the first. The fields of a record, items of a list and the value and body of a let
+// are checked on their own, so a mistake in one doesn't hide those in the others
+pub fn errors(expr: &Expr, bindings: &HashMap<Ident, Type>) -> Vec<Diagnostic> {
+    use ExprKind::*;
+    let error = match typecheck(expr, bindings) {
+        Ok(_) => return Vec::new(),
+        Err(d) => d
+    };
+    let mut found = Vec::new();
+    match &expr.expr {
+        Let(id, t, value, body) => {
+            found.extend(errors(value, bindings));
+            // if the value has no type, anything using it is checked when it's evaluated
+            let bound_type = t.clone().or_else(|| typecheck(value, bindings).ok().map(|te| te.t)).unwrap_or(Type::Any);
+            let mut new_defs = bindings.clone();
+            new_defs.insert(id.clone(), bound_type);
+            found.extend(errors(body, &new_defs));
+        },
+        TypeDecl(_, _, e) => found.extend(errors(e, bindings)),
+        Record(hm) => hm.values().for_each(|val| found.extend(errors(val, bindings))),
+        List(vec) => vec.iter().for_each(|e| found.extend(errors(e, bindings))),
+        _ => ()
+    }
+    // the first error is one of those found, unless it's with the expression itself, like a let's declared type
+    if !found.iter().any(|d| d.span == error.span && d.message == error.message) {
+        found.insert(0, error);
+    }
+    found
+}
+
+fn check(expr: &Expr, bindings: &HashMap<Ident, Type>) -> Result<TypedExpr, Diagnostic> {
     use ExprKind::*;
     match &expr.expr {
-        // TODO this won't work if let isn't typed
         Let(id, op_t, e1, e2) => {
-            let te1 = typecheck(&*e1, bindings)?;
-            match (op_t, te1.t) {
-                (None, _) => (),
-                (Some(t1), t2) if t1 == t2 => (),
-                (Some(t1), t2) => return Err(format!("Declared type {t1:#?} of {id} does not match actual type of expression, {t2:#?}"))
-            }
-            let new_defs = bindings.clone();
-            new_defs.insert(id, te1.t);
-            let te2 = typecheck(&*e2, &new_defs)?;
+            let te1 = typecheck(e1, bindings)?;
+            if let Some(t) = op_t {
+                check_refinement_types(t, bindings)?;
+            }
+            let bound_type = match op_t {
+                None => te1.t.clone(),
+                Some(Type::Ident(name)) => return Err(format!("Unknown type {name} in the declaration of {id}").into()),
+                Some(t1) if te1.t <= *t1 => t1.clone(),
+                // what the value is is only known once it's evaluated, like imported data, so it's checked against the type then
+                Some(t1) if te1.t == Type::Any => t1.clone(),
+                Some(t1) => return Err(format!("Declared type {t1:#?} of {id} does not match actual type of expression, {:#?}", te1.t).into())
+            };
+            let mut new_defs = bindings.clone();
+            new_defs.insert(id.clone(), bound_type);
+            let te2 = typecheck(e2, &new_defs)?;
+            Ok(TypedExpr {
+                t: te2.t.clone(),
+                expr: Let(id.clone(), op_t.clone(), Box::new(te1), Box::new(te2))
+            })
+        },
+        TypeDecl(_, _, e) => typecheck(e, bindings),
+        Assert(cond, message, e) => {
+            let tcond = typecheck(cond, bindings)?;
+            if tcond.t != Type::Bool && tcond.t != Type::Any {
+                return Err(format!("Boolean expected in condition of assertion. Instead got {:#?}", tcond.t).into());
+            }
+            let tmessage = typecheck(message, bindings)?;
+            if tmessage.t != Type::Text && tmessage.t != Type::Any {
+                return Err(format!("Text expected as the message of assertion. Instead got {:#?}", tmessage.t).into());
+            }
+            let te = typecheck(e, bindings)?;
             Ok(TypedExpr {
-                t: te2.t,
-                expr: Let(id, op_t, Box::new(te1), Box::new(te2))
+                t: te.t.clone(),
+                expr: Assert(Box::new(tcond), Box::new(tmessage), Box::new(te))
             })
         },
         If(b, iftrue, iffalse) => {
-            let tb = typecheck(&*b, bindings)?;
-            if tb.t != Type::Bool {
-                return Err(format!("Boolean expected in condition of if expression. Instead got {:#?}", tb.t));
+            let tb = typecheck(b, bindings)?;
+            if tb.t != Type::Bool && tb.t != Type::Any {
+                return Err(format!("Boolean expected in condition of if expression. Instead got {:#?}", tb.t).into());
             }
-            let t_true = typecheck(&*iftrue, bindings)?;
-            let t_false = typecheck(&*iffalse, bindings)?;
+            // after a null check, the identifier is known to be (or not be) null in each branch
+            let (true_bindings, false_bindings) = match null_check(b) {
+                Some((id, eq)) => match bindings.get(id) {
+                    Some(t) if is_nullable(t) => {
+                        let mut null_defs = bindings.clone();
+                        null_defs.insert(id.clone(), Type::Null);
+                        let mut non_null_defs = bindings.clone();
+                        non_null_defs.insert(id.clone(), without_null(t));
+                        if eq { (null_defs, non_null_defs) } else { (non_null_defs, null_defs) }
+                    },
+                    _ => (bindings.clone(), bindings.clone())
+                },
+                None => (bindings.clone(), bindings.clone())
+            };
+            let t_true = typecheck(iftrue, &true_bindings)?;
+            let t_false = typecheck(iffalse, &false_bindings)?;
             if t_true.t != t_false.t {
                 //TODO: this might not be what we want. For instance, if we have record types, we might want to use an alternative type. {x}|{x,y} instead of {x}
                 if t_true.t < t_false.t{
                     // types are not equal, but we can use the more specific one.
-                    return Ok(TypedExpr{
-                        t: t_true.t,
+                    Ok(TypedExpr{
+                        t: t_true.t.clone(),
                         expr: If(Box::new(tb), Box::new(t_true), Box::new(t_false))
-                    });
+                    })
                 } else if t_true.t > t_false.t {
                     // types are not equal, but we can use the more specific one.
-                    return Ok(TypedExpr{
-                        t: t_false.t,
+                    Ok(TypedExpr{
+                        t: t_false.t.clone(),
                         expr: If(Box::new(tb), Box::new(t_true), Box::new(t_false))
-                    });
+                    })
                 } else {
                     // Todo: should this be an error, or should we use the alternative type?
                     // return Err("Branches of if expression do not have the same type. True branch had type {t_true.t} and False branch had type {t_false.t}");
-                    return Ok(TypedExpr{
-                        t: Type::Alternative(Box::new(t_true.t), Box::new(t_false.t)),
+                    Ok(TypedExpr{
+                        t: Type::Alternative(Box::new(t_true.t.clone()), Box::new(t_false.t.clone())),
                         expr: If(Box::new(tb), Box::new(t_true), Box::new(t_false))
-                    });
+                    })
                 }
             } else {
                 //ok
-                return Ok(TypedExpr{
-                    t: t_true.t,
+                Ok(TypedExpr{
+                    t: t_true.t.clone(),
                     expr: If(Box::new(tb), Box::new(t_true), Box::new(t_false))
-                });
+                })
             }
         },
         App(e1, e2) => {
-            let t1 = typecheck(&*e1, bindings)?;
-            let t2 = typecheck(&*e2, bindings)?;
-            if let Type::Function(x,outtype) = t1.t {
-                if *x == t2.t {
+            let t1 = typecheck(e1, bindings)?;
+            let t2 = typecheck(e2, bindings)?;
+            // data parsed by a builtin from a literal argument has a type we can find out now
+            if let (Ident(name), Text(arg)) = (&e1.expr, &e2.expr) {
+                match Builtin::parser(name) {
+                    Some(b) if bindings.get(name) == Some(&b.t()) => {
+                        let v = b.apply(Value::Text(arg.clone()))?;
+                        return Ok(TypedExpr {
+                            t: infer_type(&v),
+                            expr: App(Box::new(t1), Box::new(t2))
+                        });
+                    },
+                    _ => ()
+                }
+            }
+            match &t1.t {
+                // an argument of type Any is checked when it's evaluated, like a let's value
+                Type::Function(x, outtype) if t2.t <= **x || t2.t == Type::Any => {
                     //ok
-                    return Ok(TypedExpr { 
-                        t: *outtype,
+                    Ok(TypedExpr {
+                        t: *outtype.clone(),
                         expr: App(Box::new(t1), Box::new(t2))
                     })
-                } else {
-                    return Err(format!("Argument to function application does not have expected type {:#?}. It instead has type {:#?}",x,t2.t));
-                }
-            } else {
-                return Err(format!("First expression in function application is not a function. It has type {:#?}",t1.t));
+                },
+                Type::Function(x, _) => Err(format!("Argument to function application does not have expected type {:#?}. It instead has type {:#?}", x, t2.t).into()),
+                // we don't know anything about the function, so we don't know anything about the result
+                Type::Any => Ok(TypedExpr {
+                    t: Type::Any,
+                    expr: App(Box::new(t1), Box::new(t2))
+                }),
+                t => Err(format!("First expression in function application is not a function. It has type {t:#?}").into())
             }
         },
         Binop(e1, bop, e2) => {
-            let t1 = typecheck(&*e1, bindings)?;
-            let t2 = typecheck(&*e2, bindings)?;
-            let new_ex = Binop(Box::new(t1), bop, Box::new(t2));
-            let new_type = match (bop, t1.t, t2.t) {
-                //TODO: can't compare all types. restrict this
+            let t1 = typecheck(e1, bindings)?;
+            let t2 = typecheck(e2, bindings)?;
+            let new_type = match (bop, &t1.t, &t2.t) {
+                // an untyped operand could be anything, so only the comparisons have a known type
+                (Bop::Eq, _, _)
+                | (Bop::Neq, _, _)
+                | (Bop::Lt, _, _)
+                | (Bop::Gt, _, _)
+                | (Bop::Lte, _, _)
+                | (Bop::Gte, _, _)
+                | (Bop::In, _, _)
+                | (Bop::NotIn, _, _) if t1.t == Type::Any || t2.t == Type::Any => Some(Type::Bool),
+                (Bop::Coalesce, a, b) => Some(without_null(a).lub(b)),
+                (_, a, b) if *a == Type::Any || *b == Type::Any => Some(Type::Any),
+                // lambdas can't be compared
+                (Bop::Eq, Type::Function(..), _)
+                | (Bop::Eq, _, Type::Function(..))
+                | (Bop::Neq, Type::Function(..), _)
+                | (Bop::Neq, _, Type::Function(..)) => None,
+                // comparing values of unrelated types is a mistake, not just false
                 (Bop::Eq, a, b) if a <= b || a >= b => Some(Type::Bool),
                 (Bop::Neq, a, b) if a <= b || a >= b => Some(Type::Bool),
-                (Bop::Lt, a, b) 
-                | (Bop::Gt, a, b) 
+                (Bop::Lt, a, b)
+                | (Bop::Gt, a, b)
                 | (Bop::Lte, a, b)
-                | (Bop::Gte, a, b) if a <= Type::Number && b <= Type::Number 
-                    || a == Type::Text && b == Type::Text => Some(Type::Bool),
+                | (Bop::Gte, a, b) if *a <= Type::Number && *b <= Type::Number
+                    || *a == Type::Text && *b == Type::Text
+                    || *a == Type::Version && *b == Type::Version => Some(Type::Bool),
                 // can only say that it's an Integer, not a natural
-                (Bop::Minus, a, b) if a <= Type::Number && b <= Type::Number => {
-                    if Type::Integer >= a && Type::Integer >= b {
+                (Bop::Minus, a, b) if *a <= Type::Number && *b <= Type::Number => {
+                    if Type::Integer >= *a && Type::Integer >= *b {
                         Some(Type::Integer)
                     } else if a >= b {
-                        Some(a)
+                        Some(a.clone())
                     } else {
-                        Some(b)
+                        Some(b.clone())
                     }
                 },
                 // Pow is only definitely a natural if both arguments are natural
                 (Bop::Pow, Type::Natural, Type::Natural) => Some(Type::Natural),
                 // Pow with any other numbers could be just a real
-                (Bop::Pow, a, b) if a <= Type::Number && b <= Type::Number => Some(Type::Number),
+                (Bop::Pow, a, b) if *a <= Type::Number && *b <= Type::Number => Some(Type::Number),
                 // Div has no guarantees. TODO: decide if this should be option type for div by 0
-                (Bop::Div, a, b) if a <= Type::Number && b <= Type::Number => Some(Type::Number),
+                (Bop::Div, a, b) if *a <= Type::Number && *b <= Type::Number => Some(Type::Number),
                 (Bop::Times, a, b)
-                | (Bop::Plus, a, b) if a <= Type::Number && b <= Type::Number => {
+                | (Bop::Plus, a, b) if *a <= Type::Number && *b <= Type::Number => {
                     if a >= b {
-                        Some(a)
+                        Some(a.clone())
                     } else {
-                        Some(b)
+                        Some(b.clone())
                     }
                 },
                 // Bitwise operations on integers or naturals
                 (Bop::And, a, b)
                 | (Bop::Or, a, b)
-                | (Bop::Xor, a, b) if a <= Type::Integer && b <= Type::Integer => {
+                | (Bop::Xor, a, b) if *a <= Type::Integer && *b <= Type::Integer => {
                     if a >= b {
-                        Some(a)
+                        Some(a.clone())
                     } else {
-                        Some(b)
+                        Some(b.clone())
                     }
                 },
                 (Bop::And, Type::Bool, Type::Bool)
                 | (Bop::Or, Type::Bool, Type::Bool)
                 | (Bop::Xor, Type::Bool, Type::Bool)  => Some(Type::Bool),
                 //Joining lists
-                (Bop::Plus, Type::List(a), Type::List(b)) if *a <= *b => Some(*b),
-                (Bop::Plus, Type::List(a), Type::List(b)) if *a >= *b => Some(*a),
-                (Bop::Plus, Type::List(a), Type::List(b)) => Some(Type::Alternative(a,b)),
+                (Bop::Plus, Type::List(a), Type::List(b)) if a <= b => Some(Type::List(b.clone())),
+                (Bop::Plus, Type::List(a), Type::List(b)) if a >= b => Some(Type::List(a.clone())),
+                (Bop::Plus, Type::List(a), Type::List(b)) => Some(Type::List(Box::new(Type::Alternative(a.clone(), b.clone())))),
                 //Joining text
                 (Bop::Plus, Type::Text, Type::Text) => Some(Type::Text),
                 //multiplying text
@@ -137,113 +242,382 @@ pub fn typecheck(expr: &Expr, bindings: &HashMap<Ident, Type>) -> Result<TypedEx
                 //When joining records, prefer the variable in the second one if there's overlap
                 (Bop::Plus, Type::Record(hm1), Type::Record(hm2)) => {
                     let mut joined_hashmap = hm1.clone();
-                    joined_hashmap.extend(hm2);
+                    joined_hashmap.extend(hm2.clone());
                     Some(Type::Record(joined_hashmap))
                 }
-                // TODO: Access for text, lists, records
+                // membership in a list, keys of a record, or substrings of text
+                (Bop::In, a, Type::List(b))
+                | (Bop::NotIn, a, Type::List(b)) if a <= b || a >= b || **b == Type::Any => Some(Type::Bool),
+                (Bop::In, Type::Text, Type::Record(_))
+                | (Bop::NotIn, Type::Text, Type::Record(_))
+                | (Bop::In, Type::Text, Type::Text)
+                | (Bop::NotIn, Type::Text, Type::Text) => Some(Type::Bool),
                 // list access. We would want this to be a natural, but maybe can't guarantee it
-                (Bop::Access, Type::List(a), Type::Integer) => Some(*a),
+                (Bop::Access, Type::List(a), b) if *b <= Type::Integer => Some(*a.clone()),
                 // String access
-                (Bop::Access, Type::Text, Type::Integer) => Some(Type::Text),
+                (Bop::Access, Type::Text, b) if *b <= Type::Integer => Some(Type::Text),
                 // Record access
-                (Bop::Access, Type::Record(hm), _) => {
-                    //TODO: What is the type of a record key?
-                    // want to allow numbers
-                    // how do we typecheck this? The expr needs to be known before typechecking works.
-                    // Some(Type::Text) //TODO: REMOVE
-                }
+                // TODO: only dot access with a known key can be typechecked
+                (Bop::Access, Type::Record(hm), _) => record_key(&t2).and_then(|key| hm.get(key).cloned()),
+                // Safe record access. A missing key or a null record gives null
+                (Bop::SafeAccess, Type::Null, _) => Some(Type::Null),
+                (Bop::SafeAccess, a, _) => record_key(&t2).and_then(|key| safe_access_type(a, key)),
                 _ => None
             };
             match new_type {
-                Some(t) => Ok(TypedExpr{t, expr: new_ex}),
-                None => Err(format!("Type of binary operation for {:#?} doesn't work. Type of e1 is {:#?} and type of e2 is {:#?}.", bop, t1.t, t2.t))
+                Some(t) => Ok(TypedExpr{t, expr: Binop(Box::new(t1), *bop, Box::new(t2))}),
+                None => Err(format!("Type of binary operation for {:#?} doesn't work. Type of e1 is {:#?} and type of e2 is {:#?}.", bop, t1.t, t2.t).into())
             }
         },
         Unop(uop, e) => {
-            let t1 = typecheck(&*e, bindings)?;
-            match (uop, t1.t) {
-                (Neg, t) if t <= Type::Number => Ok(TypedExpr{t, expr:Unop(uop,Box::new(t1))}),
-                (Not, Type::Bool) => Ok(TypedExpr{t: Type::Bool, expr:Unop(uop,Box::new(t1))}),
-                _ => Err(format!("Type of unary operation for {:#?} does not work. Type of e is {:#?}", uop, t1.t))
+            let t1 = typecheck(e, bindings)?;
+            match (uop, &t1.t) {
+                (_, Type::Any) => Ok(TypedExpr{t: Type::Any, expr:Unop(*uop,Box::new(t1))}),
+                // negating a natural makes it an integer
+                (Uop::Neg, Type::Natural) => Ok(TypedExpr{t: Type::Integer, expr:Unop(*uop,Box::new(t1))}),
+                (Uop::Neg, t) if *t <= Type::Number => Ok(TypedExpr{t: t.clone(), expr:Unop(*uop,Box::new(t1))}),
+                (Uop::Not, Type::Bool) => Ok(TypedExpr{t: Type::Bool, expr:Unop(*uop,Box::new(t1))}),
+                _ => Err(format!("Type of unary operation for {:#?} does not work. Type of e is {:#?}", uop, t1.t).into())
             }
         },
         Lambda(id,op_t,e) => {
-            //TODO: what do we do if type isn't specified in the lambda?
-            //how do we infer the type?
-            // Ok(TypedExpr{t: Type::Text, expr: Lambda(id, op_t, Box::new(typecheck(&*e, bindings)?))}) // TODO: REMOVE
+            //TODO: we don't infer the type of untyped arguments, so they are treated as Any
+            let arg_type = op_t.clone().unwrap_or(Type::Any);
+            check_refinement_types(&arg_type, bindings)?;
+            let mut new_defs = bindings.clone();
+            new_defs.insert(id.clone(), arg_type.clone());
+            let te = typecheck(e, &new_defs)?;
+            Ok(TypedExpr{
+                t: Type::Function(Box::new(arg_type), Box::new(te.t.clone())),
+                expr: Lambda(id.clone(), op_t.clone(), Box::new(te))
+            })
         },
         Record(hm) => {
-            let record_type = HashMap::new();
-            let typed_record = HashMap::new();
+            let mut record_type = RecordMap::new();
+            let mut typed_record = RecordMap::new();
             for (key, val) in hm.iter() {
-                let tval = typecheck(&*val, bindings)?;
-                record_type.insert(key.clone(),tval.t);
+                let tval = typecheck(val, bindings)?;
+                record_type.insert(key.clone(),tval.t.clone());
                 typed_record.insert(key.clone(), tval);
             }
             Ok(TypedExpr { t: Type::Record(record_type), expr: Record(typed_record) })
         },
         List(vec) => {
-            let typed_vec : Result<Vec<TypedExpr>,String> = vec.iter().map(|e| typecheck(e, &bindings)).collect();
+            let typed_vec : Result<Vec<TypedExpr>,Diagnostic> = vec.iter().map(|e| typecheck(e, bindings)).collect();
             let typed_vec = typed_vec?;
             // TODO: I don't think this is what we want.
-            if vec.len() == 0 {
+            if vec.is_empty() {
                 return Ok(TypedExpr {
                     t: Type::List(Box::new(Type::Any)),
                     expr: List(typed_vec)
                 });
             }
-            let lub_type = typed_vec.iter().map(|typed_expr| typed_expr.t).reduce(|acc, t| acc.lub(&t));
+            let lub_type = typed_vec.iter().map(|typed_expr| typed_expr.t.clone()).reduce(|acc, t| acc.lub(&t));
             match lub_type {
                 None => unreachable!(),
                 Some(t) => Ok(TypedExpr {
-                    t,
+                    t: Type::List(Box::new(t)),
                     expr: List(typed_vec)
                 })
             }
         },
         Ident(id) => {
-            match bindings.get(&id) {
-                None => Err(format!("identifier {id} does not have a type")),
+            match bindings.get(id) {
+                None => Err(format!("identifier {id} does not have a type").into()),
                 Some(t) => Ok(
                     TypedExpr{
                         t: t.clone(),
-                        expr: Ident(id)
+                        expr: Ident(id.clone())
                     }
                 )
             }
-        },        
+        },
+        // literals get their type from the parser
         Text(s) => {
             Ok(TypedExpr{
-                t: Type::Text,
-                expr: Text(s)
+                t: expr.t.clone().unwrap_or(Type::Text),
+                expr: Text(s.clone())
+            })
+        },
+        Int(num) => {
+            Ok(TypedExpr{
+                t: expr.t.clone().unwrap_or(Type::Integer),
+                expr: Int(*num)
             })
         },
-        Number(num) => {
+        Float(num) => {
             Ok(TypedExpr{
-                t: Type::Number,
-                expr: Number(num)
+                t: expr.t.clone().unwrap_or(Type::Real),
+                expr: Float(*num)
             })
         },
         Boolean(b) => {
             Ok(TypedExpr{
-                t: Type::Bool,
-                expr: Boolean(b)
+                t: expr.t.clone().unwrap_or(Type::Bool),
+                expr: Boolean(*b)
             })
         },
-        
         Null => {
             Ok(TypedExpr{
-                t: Type::Null,
+                t: expr.t.clone().unwrap_or(Type::Null),
                 expr: Null
             })
         },
+        Color(c) => {
+            Ok(TypedExpr{
+                t: expr.t.clone().unwrap_or(Type::Color),
+                expr: Color(*c)
+            })
+        },
+        Version(v) => {
+            Ok(TypedExpr{
+                t: expr.t.clone().unwrap_or(Type::Version),
+                expr: Version(v.clone())
+            })
+        },
+    }
+}
+
+// replaces the names of type aliases with the types they stand for, and removes the declarations
+pub fn resolve_aliases(expr: &Expr, aliases: &HashMap<Ident, Type>) -> Result<Expr, String> {
+    use ExprKind::*;
+    let resolve = |e: &Expr| resolve_aliases(e, aliases).map(Box::new);
+    let resolve_opt = |t: &Option<Type>| t.as_ref().map(|t| resolve_type(t, aliases)).transpose();
+    let kind = match &expr.expr {
+        TypeDecl(name, t, e) => {
+            // a declaration can use the aliases declared before it
+            let mut new_aliases = aliases.clone();
+            new_aliases.insert(name.clone(), resolve_type(t, aliases)?);
+            return resolve_aliases(e, &new_aliases);
+        },
+        Let(id, t, e1, e2) => Let(id.clone(), resolve_opt(t)?, resolve(e1)?, resolve(e2)?),
+        Lambda(id, t, e) => Lambda(id.clone(), resolve_opt(t)?, resolve(e)?),
+        If(b, e1, e2) => If(resolve(b)?, resolve(e1)?, resolve(e2)?),
+        Assert(cond, message, e) => Assert(resolve(cond)?, resolve(message)?, resolve(e)?),
+        App(e1, e2) => App(resolve(e1)?, resolve(e2)?),
+        Binop(e1, bop, e2) => Binop(resolve(e1)?, *bop, resolve(e2)?),
+        Unop(uop, e) => Unop(*uop, resolve(e)?),
+        Record(hm) => {
+            let mut resolved = RecordMap::new();
+            for (k, v) in hm {
+                resolved.insert(k.clone(), resolve_aliases(v, aliases)?);
+            }
+            Record(resolved)
+        },
+        List(l) => List(l.iter().map(|e| resolve_aliases(e, aliases)).collect::<Result<Vec<Expr>, String>>()?),
+        e => e.clone()
+    };
+    Ok(Expr { t: expr.t.clone(), span: expr.span, expr: kind })
+}
+
+fn resolve_type(t: &Type, aliases: &HashMap<Ident, Type>) -> Result<Type, String> {
+    let resolve = |t: &Type| resolve_type(t, aliases).map(Box::new);
+    match t {
+        Type::Ident(name) => aliases.get(name).cloned().ok_or_else(|| format!("Unknown type {name}")),
+        Type::List(t) => Ok(Type::List(resolve(t)?)),
+        Type::Function(a, b) => Ok(Type::Function(resolve(a)?, resolve(b)?)),
+        Type::Alternative(a, b) => Ok(Type::Alternative(resolve(a)?, resolve(b)?)),
+        Type::Record(hm) => {
+            let mut resolved = RecordMap::new();
+            for (k, v) in hm {
+                resolved.insert(k.clone(), resolve_type(v, aliases)?);
+            }
+            Ok(Type::Record(resolved))
+        },
+        Type::Refined(t, refinement) => Ok(Type::Refined(resolve(t)?, Refinement {
+            binder: refinement.binder.clone(),
+            pred: Box::new(resolve_aliases(&refinement.pred, aliases)?)
+        })),
+        t => Ok(t.clone())
+    }
+}
+
+// the most specific type of a value
+pub fn infer_type(v: &Value) -> Type {
+    match v {
+        Value::Null => Type::Null,
+        Value::Boolean(_) => Type::Bool,
+        Value::Int(n) if *n >= 0 => Type::Natural,
+        Value::Int(_) => Type::Integer,
+        Value::Float(_) => Type::Real,
+        Value::Text(_) => Type::Text,
+        Value::Color(_) => Type::Color,
+        Value::Version(_) => Type::Version,
+        Value::List(l) => {
+            let t = l.iter().map(infer_type).reduce(|acc, t| acc.lub(&t));
+            Type::List(Box::new(t.unwrap_or(Type::Any)))
+        },
+        Value::Record(hm) => Type::Record(hm.iter().map(|(k, v)| (k.clone(), infer_type(v))).collect()),
+        Value::Lambda(..) | Value::Builtin(_) => Type::Any
+    }
+}
+
+// a value whose type is only known once it's evaluated against the type it's declared to have.
+// the name is what the value is called in the message, and is extended with the fields and indices inside it
+pub fn check_value(v: &Value, t: &Type, name: &str) -> Result<(), String> {
+    let mismatch = || format!("{name} is {}, which isn't {}", printer::value_source(v), printer::type_source(t));
+    match (t, v) {
+        (Type::Any, _) => Ok(()),
+        (Type::Refined(base, _), v) => check_value(v, base, name),
+        (Type::Alternative(a, b), v) => check_value(v, a, name).or_else(|_| check_value(v, b, name)).map_err(|_| mismatch()),
+        (Type::Function(..), Value::Lambda(..) | Value::Builtin(_)) => Ok(()),
+        (Type::List(t), Value::List(l)) => l.iter().enumerate().try_for_each(|(i, v)| check_value(v, t, &format!("{name}[{i}]"))),
+        // like in the typechecker, a record can have more fields than its type
+        (Type::Record(fields), Value::Record(hm)) => fields.iter().try_for_each(|(k, t)| match hm.get(k) {
+            Some(v) => check_value(v, t, &format!("{name}.{}", printer::key_source(k))),
+            None => Err(format!("{name} has no field {}, which has to be {}", printer::key_source(k), printer::type_source(t)))
+        }),
+        (Type::List(_) | Type::Record(_) | Type::Function(..), _) => Err(mismatch()),
+        (t, v) if infer_type(v) <= *t => Ok(()),
+        _ => Err(mismatch())
+    }
+}
+
+// refinements must be boolean expressions of the refined value
+fn check_refinement_types(t: &Type, bindings: &HashMap<Ident, Type>) -> Result<(), Diagnostic> {
+    match t {
+        Type::Refined(base, refinement) => {
+            check_refinement_types(base, bindings)?;
+            let mut new_defs = bindings.clone();
+            new_defs.insert(refinement.binder.clone(), (**base).clone());
+            let tpred = typecheck(&refinement.pred, &new_defs)?;
+            if tpred.t != Type::Bool && tpred.t != Type::Any {
+                let message = format!("Boolean expected in refinement of {}. Instead got {:#?}", refinement.binder, tpred.t);
+                return Err(Diagnostic::new(refinement.pred.span, &message, None));
+            }
+            Ok(())
+        },
+        Type::List(t) => check_refinement_types(t, bindings),
+        Type::Function(a, b)
+        | Type::Alternative(a, b) => {
+            check_refinement_types(a, bindings)?;
+            check_refinement_types(b, bindings)
+        },
+        Type::Record(hm) => hm.values().try_for_each(|t| check_refinement_types(t, bindings)),
+        _ => Ok(())
+    }
+}
+
+// the key of a record access, if it is known before evaluation
+fn record_key(e: &TypedExpr) -> Option<&Ident> {
+    match &e.expr {
+        ExprKind::Text(key) => Some(key),
+        _ => None
+    }
+}
+
+fn safe_access_type(t: &Type, key: &Ident) -> Option<Type> {
+    match t {
+        Type::Null => Some(Type::Null),
+        Type::Record(hm) => match hm.get(key) {
+            Some(v) if is_nullable(v) => Some(v.clone()),
+            Some(v) => Some(Type::Alternative(Box::new(v.clone()), Box::new(Type::Null))),
+            None => Some(Type::Null)
+        },
+        Type::Alternative(a, b) => {
+            let ta = safe_access_type(a, key)?;
+            let tb = safe_access_type(b, key)?;
+            Some(ta.lub(&tb))
+        },
+        _ => None
+    }
+}
+
+fn is_nullable(t: &Type) -> bool {
+    match t {
+        Type::Null | Type::Any => true,
+        Type::Alternative(a, b) => is_nullable(a) || is_nullable(b),
+        _ => false
     }
 }
 
-fn check() {
+// T | Null becomes T
+fn without_null(t: &Type) -> Type {
+    match t {
+        Type::Alternative(a, b) => match (**a == Type::Null, **b == Type::Null) {
+            (true, true) => Type::Null,
+            (true, false) => without_null(b),
+            (false, true) => without_null(a),
+            (false, false) => Type::Alternative(Box::new(without_null(a)), Box::new(without_null(b)))
+        },
+        t => t.clone()
+    }
+}
 
+// recognizes `x == null` and `x != null` (in either order)
+// returns the identifier and whether the check is for equality
+fn null_check(cond: &Expr) -> Option<(&Ident, bool)> {
+    if let ExprKind::Binop(e1, bop, e2) = &cond.expr {
+        let eq = match bop {
+            Bop::Eq => true,
+            Bop::Neq => false,
+            _ => return None
+        };
+        match (&e1.expr, &e2.expr) {
+            (ExprKind::Ident(id), ExprKind::Null) | (ExprKind::Null, ExprKind::Ident(id)) => Some((id, eq)),
+            _ => None
+        }
+    } else {
+        None
+    }
 }
 
-fn infer() {
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the type of the source, written as it would be in a declaration, or the error
+    fn type_of(source: &str) -> Result<String, String> {
+        let e = crate::parser::parse(source).unwrap();
+        typecheck(&e, &crate::builtins::types()).map(|te| printer::type_source(&te.t)).map_err(|d| d.message)
+    }
+
+    fn type_errors(source: &str) -> Vec<String> {
+        let e = crate::parser::parse(source).unwrap();
+        errors(&e, &crate::builtins::types()).into_iter().map(|d| d.message).collect()
+    }
+
+    #[test]
+    fn every_field_and_item_with_a_type_error_is_reported() {
+        let found = type_errors(r#"let n = 1 in { a = n + "x", b = [true, !1], c = n + 1 }"#);
+        assert_eq!(found.len(), 2, "{found:?}");
+        assert!(found[0].contains("binary operation"));
+        assert!(found[1].contains("unary operation"));
+        assert!(type_errors(r#"{ a = 1 + 1 }"#).is_empty());
+    }
+
+    #[test]
+    fn null_checks_narrow_optional_types() {
+        assert_eq!(type_of("let x : Natural? = 1 in if (x == null) then 0 else (x + 1)"), Ok("Natural".to_string()));
+        assert_eq!(type_of("let x : Natural? = 1 in if (x != null) then (x + 1) else 0"), Ok("Natural".to_string()));
+        assert_eq!(type_of("let x : Natural? = 1 in if (null == x) then 0 else x"), Ok("Natural".to_string()));
+        assert!(type_of("let x : Natural? = 1 in if (x == null) then (x + 1) else 0").is_err());
+    }
+
+    #[test]
+    fn optional_values_must_be_checked_before_use() {
+        assert!(type_of("let x : Natural? = 1 in x + 1").is_err());
+        assert_eq!(type_of("let x : Natural? = null in x ?? 0"), Ok("Natural".to_string()));
+        assert_eq!(type_of("let r : { a : Text }? = null in r?.a"), Ok("Text?".to_string()));
+        assert_eq!(type_of("let r = { a = { b = 1 } } in r?.a?.b"), Ok("Natural?".to_string()));
+    }
+
+    #[test]
+    fn only_related_types_can_be_compared() {
+        assert!(type_of(r#""a" == 1"#).is_err());
+        assert!(type_of(r#""a" != 1"#).is_err());
+        assert!(type_of(r"(\x -> x) == (\x -> x)").is_err());
+        assert_eq!(type_of("1 == 1.5"), Ok("Bool".to_string()));
+        assert_eq!(type_of("{ a = 1 } == { a = 2 }"), Ok("Bool".to_string()));
+    }
 
-}
\ No newline at end of file
+    #[test]
+    fn membership_works_on_lists_records_and_text() {
+        assert_eq!(type_of("1 in [1, 2]"), Ok("Bool".to_string()));
+        assert_eq!(type_of(r#""a" in { a = 1 }"#), Ok("Bool".to_string()));
+        assert_eq!(type_of(r#""b" not in "abc""#), Ok("Bool".to_string()));
+        assert!(type_of(r#""a" in [1, 2]"#).is_err());
+        assert!(type_of("1 in { a = 1 }").is_err());
+        assert!(type_of("1 not in 2").is_err());
+    }
+}